@@ -2,7 +2,10 @@
 #![allow(non_camel_case_types)]
 #![allow(non_snake_case)]
 
-use immix_rust::{gc_count, set_low_water_mark, ImmixMutatorLocal, ImmixSpace};
+use immix_rust::{
+    gc_count, gc_pause_time, last_retention_report, max_gc_pause, set_low_water_mark, Address,
+    ImmixMutatorLocal,
+};
 use std::alloc::Layout;
 use std::mem::size_of;
 use std::time::Instant;
@@ -44,8 +47,10 @@ fn Populate(iDepth: i32, thisNode: *mut Node, mutator: &mut ImmixMutatorLocal) {
         return;
     }
     unsafe {
-        (*thisNode).left = alloc(mutator);
-        (*thisNode).right = alloc(mutator);
+        let left = alloc(mutator);
+        write_field(mutator, thisNode, &mut (*thisNode).left, left);
+        let right = alloc(mutator);
+        write_field(mutator, thisNode, &mut (*thisNode).right, right);
         Populate(iDepth - 1, (*thisNode).left, mutator);
         Populate(iDepth - 1, (*thisNode).right, mutator);
     }
@@ -88,10 +93,33 @@ fn TimeConstruction(depth: i32, mutator: &mut ImmixMutatorLocal) {
     println!("\tButtom up construction took {:?}", elapsed);
 }
 
+// thisNode may have survived a collection already, so stores go through the write barrier
+#[inline(always)]
+fn write_field(
+    mutator: &mut ImmixMutatorLocal,
+    node: *mut Node,
+    field: *mut *mut Node,
+    value: *mut Node,
+) {
+    unsafe {
+        mutator.write_ref(
+            Address::from_ptr(node).to_object_reference(),
+            Address::from_ptr(field),
+            Address::from_ptr(value).to_object_reference(),
+        );
+    }
+}
+
 #[inline(always)]
 fn alloc(mutator: &mut ImmixMutatorLocal) -> *mut Node {
     let addr = mutator.alloc(Layout::new::<Node>());
     mutator.init_object(addr, 0b1100_0011);
+    // the memory may hold stale references, which the collector would follow
+    init_Node(
+        addr.to_ptr_mut::<Node>(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
     //    objectmodel::init_header(unsafe{addr.to_object_reference()}, HEADER_INIT_U64);
     addr.to_ptr_mut::<Node>()
 }
@@ -101,7 +129,7 @@ pub fn start(space_size: usize) {
 
     set_low_water_mark();
 
-    let immix_space = Arc::new(ImmixSpace::new(space_size));
+    let immix_space = Arc::new(crate::new_immix_space(space_size));
 
     let mut mutator = ImmixMutatorLocal::new(immix_space);
 
@@ -154,5 +182,13 @@ pub fn start(space_size: usize) {
 
     PrintDiagnostics();
     println!("Completed in {:?}", elapsed);
-    println!("Finished with {} collections", gc_count());
+    println!(
+        "Finished with {} collections, paused for {:?} ({:?} at most)",
+        gc_count(),
+        gc_pause_time(),
+        max_gc_pause()
+    );
+    if let Some(report) = last_retention_report() {
+        println!("{}", report);
+    }
 }
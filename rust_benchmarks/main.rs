@@ -1,5 +1,7 @@
+use immix_rust::{BlockGeometry, BlockPolicy, ImmixSpace, MarkingBudget, WriteBarrier};
 use std::env;
 use std::mem::size_of;
+use std::time::Duration;
 
 mod exhaust;
 mod gcbench;
@@ -36,6 +38,72 @@ fn main() {
         }
     }
 
+    if let Some(interval) = sticky_immix_interval() {
+        println!(
+            "sticky mark bits, full heap collection every {} collections",
+            interval
+        );
+    }
+
+    if let Some(interval) = rc_immix_interval() {
+        println!(
+            "reference counting, backup trace every {} collections",
+            interval
+        );
+    }
+
+    if concurrent_marking() {
+        println!("concurrent marking");
+    }
+
+    if let Some(budget) = incremental_marking_budget() {
+        println!("incremental marking, {:?} per slice", budget);
+    }
+
+    if concurrent_sweeping() {
+        println!("concurrent sweeping");
+    }
+
+    if lazy_sweeping() {
+        println!("lazy sweeping");
+    }
+
+    if let Some(policy) = block_policy() {
+        println!("block policy: {:?} blocks first", policy);
+    }
+
+    if let Some(n_gcthreads) = gc_threads() {
+        println!("{} GC threads", n_gcthreads);
+    }
+
+    if let Some(depth) = trace_prefetch() {
+        println!("prefetching {} objects ahead while tracing", depth);
+    }
+
+    if let Some(barrier) = write_barrier() {
+        println!("{:?} write barrier", barrier);
+    }
+
+    if let Some(nursery_size) = nursery_size() {
+        println!(
+            "copying nursery of {} bytes, taken from the heap",
+            nursery_size
+        );
+    }
+
+    if let Some(geometry) = block_geometry() {
+        println!(
+            "{} byte lines in {} byte blocks",
+            geometry.bytes_in_line(),
+            geometry.bytes_in_block()
+        );
+    }
+
+    if env::var("RETENTION_REPORT").is_ok() {
+        println!("reporting conservative retention of the last collection");
+        immix_rust::set_retention_report(true);
+    }
+
     println!("The current machine has {} cpus!", num_cpus::get());
     println!("Program compiled in {}x mode!", 8 * size_of::<*mut ()>());
 
@@ -57,3 +125,159 @@ fn main() {
         println!("unknown features: build with 'cargo build --release --features \"exhaust\"");
     }
 }
+
+/// `STICKY_IMMIX=<n>` turns on sticky mark bits for the gcbench benchmarks, with a full heap
+/// collection every n collections.
+pub fn sticky_immix_interval() -> Option<usize> {
+    env::var("STICKY_IMMIX").ok().map(|x| x.parse().unwrap())
+}
+
+/// `RC_IMMIX=<n>` turns on reference counting for the gcbench benchmarks, with a backup trace
+/// every n collections.
+pub fn rc_immix_interval() -> Option<usize> {
+    env::var("RC_IMMIX").ok().map(|x| x.parse().unwrap())
+}
+
+/// `CONCURRENT_MARKING=1` turns on concurrent marking for the gcbench benchmarks.
+pub fn concurrent_marking() -> bool {
+    env::var("CONCURRENT_MARKING").is_ok()
+}
+
+/// `CONCURRENT_SWEEPING=1` lets sweeping continue after mutators resume in the gcbench benchmarks.
+pub fn concurrent_sweeping() -> bool {
+    env::var("CONCURRENT_SWEEPING").is_ok()
+}
+
+/// `LAZY_SWEEPING=1` leaves sweeping to the gcbench mutators, which sweep blocks as they take them.
+pub fn lazy_sweeping() -> bool {
+    env::var("LAZY_SWEEPING").is_ok()
+}
+
+/// `BLOCK_POLICY=recyclable|free` chooses which blocks the mutators of the gcbench benchmarks
+/// allocate into first.
+pub fn block_policy() -> Option<BlockPolicy> {
+    env::var("BLOCK_POLICY").ok().map(|x| match x.as_str() {
+        "recyclable" => BlockPolicy::Recyclable,
+        "free" => BlockPolicy::Free,
+        _ => panic!("unknown block policy: {}", x),
+    })
+}
+
+/// `INCREMENTAL_MARKING=<n>us` (or `<n>` objects) turns on incremental marking for the gcbench
+/// benchmarks, with slices of n microseconds (or n objects scanned).
+pub fn incremental_marking_budget() -> Option<MarkingBudget> {
+    env::var("INCREMENTAL_MARKING")
+        .ok()
+        .map(|x| match x.strip_suffix("us") {
+            Some(us) => MarkingBudget::Time(Duration::from_micros(us.parse().unwrap())),
+            None => MarkingBudget::Work(x.parse().unwrap()),
+        })
+}
+
+/// `GC_THREADS=<n>` sets the number of GC threads of the gcbench benchmarks.
+pub fn gc_threads() -> Option<usize> {
+    env::var("GC_THREADS").ok().map(|x| x.parse().unwrap())
+}
+
+/// `TRACE_PREFETCH=<n>` sets the depth of the prefetch buffer of the GC threads of the gcbench
+/// benchmarks (0 turns prefetching off).
+pub fn trace_prefetch() -> Option<usize> {
+    env::var("TRACE_PREFETCH").ok().map(|x| x.parse().unwrap())
+}
+
+/// `WRITE_BARRIER=object|field|card|soft-dirty` chooses what the write barrier of the gcbench
+/// benchmarks records, for the modes which need one.
+pub fn write_barrier() -> Option<WriteBarrier> {
+    env::var("WRITE_BARRIER").ok().map(|x| match x.as_str() {
+        "object" => WriteBarrier::Object,
+        "field" => WriteBarrier::Field,
+        "card" => WriteBarrier::Card,
+        "soft-dirty" => WriteBarrier::SoftDirty,
+        _ => panic!("unknown write barrier: {}", x),
+    })
+}
+
+/// `NURSERY_SIZE=<n>M` gives the gcbench benchmarks a copying nursery of n MB, taken out of the
+/// heap size (GenImmix).
+pub fn nursery_size() -> Option<usize> {
+    env::var("NURSERY_SIZE")
+        .ok()
+        .map(|x| x.trim_end_matches('M').parse::<usize>().unwrap() << 20)
+}
+
+/// `LINE_SIZE=<n>` and `BLOCK_SIZE=<n>K` set the sizes of the lines and blocks of the gcbench
+/// benchmarks, in bytes and KB. Either one left out keeps its default size.
+pub fn block_geometry() -> Option<BlockGeometry> {
+    let line = env::var("LINE_SIZE")
+        .ok()
+        .map(|x| x.parse::<usize>().unwrap());
+    let block = env::var("BLOCK_SIZE")
+        .ok()
+        .map(|x| x.trim_end_matches('K').parse::<usize>().unwrap() << 10);
+    if line.is_none() && block.is_none() {
+        return None;
+    }
+
+    let log2 = |bytes: usize| {
+        assert!(
+            bytes.is_power_of_two(),
+            "line and block sizes must be powers of two"
+        );
+        bytes.trailing_zeros() as usize
+    };
+    let default = BlockGeometry::default();
+    Some(BlockGeometry::new(
+        line.map_or(default.log_bytes_in_line(), log2),
+        block.map_or(default.log_bytes_in_block(), log2),
+    ))
+}
+
+/// Creates the space for the gcbench benchmarks, set up as asked for by `NURSERY_SIZE`,
+/// `LINE_SIZE`, `BLOCK_SIZE`, `GC_THREADS`, `TRACE_PREFETCH`, `WRITE_BARRIER`, `STICKY_IMMIX`,
+/// `RC_IMMIX`, `CONCURRENT_MARKING`, `INCREMENTAL_MARKING`, `CONCURRENT_SWEEPING`,
+/// `LAZY_SWEEPING` and `BLOCK_POLICY`.
+pub fn new_immix_space(space_size: usize) -> ImmixSpace {
+    let geometry = block_geometry().unwrap_or_default();
+    let immix_space = match nursery_size() {
+        Some(nursery_size) => {
+            ImmixSpace::with_geometry(space_size - nursery_size, nursery_size, geometry)
+        }
+        None => ImmixSpace::with_geometry(space_size, 0, geometry),
+    };
+    if let Some(n_gcthreads) = gc_threads() {
+        immix_space.set_gc_threads(n_gcthreads);
+    }
+    if let Some(depth) = trace_prefetch() {
+        immix_space.set_trace_prefetch(depth);
+    }
+    match write_barrier() {
+        Some(WriteBarrier::SoftDirty) => immix_space
+            .enable_soft_dirty_tracking()
+            .expect("soft-dirty tracking is not available"),
+        Some(barrier) => immix_space.set_write_barrier(barrier),
+        None => {}
+    }
+    if let Some(interval) = sticky_immix_interval() {
+        immix_space.enable_sticky_immix(interval);
+    }
+    if let Some(interval) = rc_immix_interval() {
+        immix_space.enable_ref_counting(interval);
+    }
+    if concurrent_marking() {
+        immix_space.enable_concurrent_marking();
+    }
+    if let Some(budget) = incremental_marking_budget() {
+        immix_space.enable_incremental_marking(budget);
+    }
+    if concurrent_sweeping() {
+        immix_space.enable_concurrent_sweeping();
+    }
+    if lazy_sweeping() {
+        immix_space.enable_lazy_sweeping();
+    }
+    if let Some(policy) = block_policy() {
+        immix_space.set_block_policy(policy);
+    }
+
+    immix_space
+}
@@ -7,7 +7,10 @@ use std::alloc::Layout;
 use std::sync::Arc;
 use std::thread;
 
-use immix_rust::{gc_count, set_low_water_mark, ImmixMutatorLocal, ImmixSpace};
+use immix_rust::{
+    gc_count, gc_pause_time, last_retention_report, max_gc_pause, set_low_water_mark, Address,
+    ImmixMutatorLocal, ImmixSpace,
+};
 
 use std::mem::size_of;
 
@@ -19,9 +22,9 @@ const kMaxTreeDepth: usize = 21;
 
 #[repr(C)] // Enforce field ordering
 struct Node {
-    _header: u64,
     left: *mut Node,
     right: *mut Node,
+    _header: u64,
     _i: i32,
     _j: i32,
 }
@@ -46,8 +49,10 @@ fn Populate(iDepth: usize, thisNode: *mut Node, mutator: &mut ImmixMutatorLocal)
         return;
     }
     unsafe {
-        (*thisNode).left = alloc(mutator);
-        (*thisNode).right = alloc(mutator);
+        let left = alloc(mutator);
+        write_field(mutator, thisNode, &mut (*thisNode).left, left);
+        let right = alloc(mutator);
+        write_field(mutator, thisNode, &mut (*thisNode).right, right);
         Populate(iDepth - 1, (*thisNode).left, mutator);
         Populate(iDepth - 1, (*thisNode).right, mutator);
     }
@@ -109,10 +114,34 @@ fn run_one_test(immix_space: Arc<ImmixSpace>) {
     mutator.destroy();
 }
 
+// thisNode may have survived a collection already, so stores go through the write barrier
+#[inline(always)]
+fn write_field(
+    mutator: &mut ImmixMutatorLocal,
+    node: *mut Node,
+    field: *mut *mut Node,
+    value: *mut Node,
+) {
+    unsafe {
+        mutator.write_ref(
+            Address::from_ptr(node).to_object_reference(),
+            Address::from_ptr(field),
+            Address::from_ptr(value).to_object_reference(),
+        );
+    }
+}
+
 #[inline(always)]
 fn alloc(mutator: &mut ImmixMutatorLocal) -> *mut Node {
     let addr = mutator.alloc(Layout::new::<Node>());
+    // `left` and `right` are the first two words, the only reference slots of the node
     mutator.init_object(addr, 0b1100_0011);
+    // the memory may hold stale references, which the collector would follow
+    init_Node(
+        addr.to_ptr_mut::<Node>(),
+        std::ptr::null_mut(),
+        std::ptr::null_mut(),
+    );
     addr.to_ptr_mut::<Node>()
 }
 
@@ -123,7 +152,7 @@ pub fn start(space_size: usize) {
 
     let n_threads: usize = num_cpus::get();
 
-    let immix_space = Arc::new(ImmixSpace::new(space_size));
+    let immix_space = Arc::new(crate::new_immix_space(space_size));
 
     // let mut mutator = ImmixMutatorLocal::new(immix_space.clone());
 
@@ -179,5 +208,13 @@ pub fn start(space_size: usize) {
     let elapsed = time_start.elapsed();
 
     println!("Completed in {:?}", elapsed);
-    println!("Finished with {} collections", gc_count());
+    println!(
+        "Finished with {} collections, paused for {:?} ({:?} at most)",
+        gc_count(),
+        gc_pause_time(),
+        max_gc_pause()
+    );
+    if let Some(report) = last_retention_report() {
+        println!("{}", report);
+    }
 }
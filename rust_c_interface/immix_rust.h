@@ -2,15 +2,35 @@
 #include <stdbool.h>
 #include <stdlib.h>
 
+// the line and block sizes of a space, see src/heap/immix/geometry.rs
+struct BlockGeometry {
+    uint64_t log_bytes_in_line;
+    uint64_t log_bytes_in_block;
+};
+
 // the layout of this struct needs to be the same as src/heap/immix/immix_mutator.rs
 struct Mutator {
+    // a generational_arena::Index, of which only the index is of any use here
     uint64_t id;
-    void* alloc_map;
+    uint64_t id_generation;
+    // one byte per word, see src/objectmodel.rs
+    uint8_t* alloc_map;
+    // one bit per word, set where an object starts
+    uint8_t* object_starts;
     uint64_t space_start;
     uint64_t cursor;
     uint64_t limit;
     uint64_t line;
 
+    // one bit per word, see src/heap/immix/barrier.rs
+    const uint64_t* unlogged_bits;
+    // one byte per 512 bytes, see src/heap/immix/card_table.rs
+    uint8_t* cards;
+    uint64_t barrier;
+    // set while concurrent marking runs, see src/heap/immix/satb.rs
+    const bool* marking;
+    struct BlockGeometry geometry;
+
     bool* yield;
     // we do not care about the rest
 };
@@ -22,6 +42,23 @@ extern void drop_mutator(struct Mutator* mutator);
 extern void yieldpoint_slow(struct Mutator** mutator);
 extern uint64_t alloc_slow(struct Mutator** mutator, uint64_t size, uint64_t align);
 extern uint64_t alloc_large(struct Mutator** mutator, uint64_t size);
+extern void write_ref_slow(struct Mutator** mutator, uint64_t src, uint64_t* slot, uint64_t value);
+// pins nest: an object stays pinned until every pin_object is matched by an unpin_object
+extern void pin_object(struct Mutator** mutator, uint64_t obj);
+extern void unpin_object(struct Mutator** mutator, uint64_t obj);
+
+// values of Mutator.barrier
+#define WRITE_BARRIER_OBJECT 0
+#define WRITE_BARRIER_FIELD  1
+#define WRITE_BARRIER_CARD   2
+// stores do not have to go through write_ref with this one
+#define WRITE_BARRIER_SOFT_DIRTY 3
+
+#define LOG_BYTES_IN_CARD 9
+#define CARD_DIRTY        1
+
+// words are always 8 bytes, unlike lines and blocks, whose sizes are chosen per space
+#define LOG_BYTES_IN_WORD 3
 
 inline void yieldpoint(bool* take_yield, struct Mutator** m) __attribute__((always_inline));
 inline void yieldpoint(bool* take_yield, struct Mutator** m) {
@@ -38,6 +75,11 @@ inline uint64_t align_up(uint64_t addr, uint64_t align) {
 inline uint64_t alloc(struct Mutator** mutator, uint64_t size, uint64_t align) __attribute__((always_inline));
 inline uint64_t alloc(struct Mutator** mutator, uint64_t size, uint64_t align) {
     struct Mutator* self = *mutator;
+    // objects larger than a line take the slow path, which allocates them past the current hole
+    // when they do not fit in it
+    if (size > (1ULL << self->geometry.log_bytes_in_line))
+        return alloc_slow(mutator, size, align);
+
     uint64_t start = align_up(self->cursor, align);
     uint64_t end = start + size;
 
@@ -48,3 +90,30 @@ inline uint64_t alloc(struct Mutator** mutator, uint64_t size, uint64_t align) {
         return start;
     }
 }
+
+#define OBJ_START_BIT 6
+
+// encode is laid out as in src/objectmodel.rs, with OBJ_START_BIT set
+inline void init_object(struct Mutator** mutator, uint64_t addr, uint8_t encode) __attribute__((always_inline));
+inline void init_object(struct Mutator** mutator, uint64_t addr, uint8_t encode) {
+    struct Mutator* self = *mutator;
+    uint64_t word = (addr - self->space_start) >> LOG_BYTES_IN_WORD;
+    self->alloc_map[word] = encode & ~(1 << OBJ_START_BIT);
+    // GC threads update the same bytes while mutators run
+    __atomic_fetch_or(&self->object_starts[word >> 3], 1 << (word & 7), __ATOMIC_RELAXED);
+}
+
+inline void write_ref(struct Mutator** mutator, uint64_t src, uint64_t* slot, uint64_t value) __attribute__((always_inline));
+inline void write_ref(struct Mutator** mutator, uint64_t src, uint64_t* slot, uint64_t value) {
+    struct Mutator* self = *mutator;
+    if (self->barrier == WRITE_BARRIER_CARD)
+        self->cards[(src - self->space_start) >> LOG_BYTES_IN_CARD] = CARD_DIRTY;
+
+    uint64_t logged = self->barrier == WRITE_BARRIER_FIELD ? (uint64_t) slot : src;
+    uint64_t word = (logged - self->space_start) >> LOG_BYTES_IN_WORD;
+
+    if ((self->unlogged_bits[word >> 6] & (1ULL << (word & 63))) || *self->marking)
+        write_ref_slow(mutator, src, slot, value);
+    else
+        *slot = value;
+}
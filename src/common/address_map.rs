@@ -1,140 +1,259 @@
-use std::alloc::{GlobalAlloc, Layout, System};
-use std::cell::Cell;
-use std::mem::size_of;
-use std::ops::{Index, IndexMut};
-use std::sync::atomic::{AtomicU8, Ordering};
-
 use crate::common::Address;
-use crate::common::LOG_POINTER_SIZE;
+use crate::common::SideMetadata;
+
+use std::hint::spin_loop;
+use std::sync::atomic::{fence, AtomicU8, Ordering};
 
-pub struct AddressMap<T> {
-    start: Address,
-    end: Address,
+// blocks nothing was ever marked in are at epoch 0
+const FIRST_EPOCH: u8 = 1;
+// the bits of a block are being cleared by the thread which first marks something in it
+const CLEARING: u8 = u8::MAX;
 
-    pub ptr: *mut T,
-    layout: Layout,
+/// Mark bits for every word of a space, kept in a 1-bit side metadata table. Instead of clearing
+/// every bit before a full trace, each block records the mark epoch its bits belong to. Bits of a
+/// block left in an older epoch read as unmarked, and are only cleared once something in the block
+/// is marked again, so unmarking everything just advances the epoch, and blocks holding nothing
+/// live are never touched. Every 254 epochs the bits are cleared for real, and block epochs start
+/// over.
+pub struct TraceMap {
+    map: SideMetadata<1>,
+    // the epoch the bits of each block belong to
+    block_epochs: SideMetadata<8>,
+    log_bytes_in_block: usize,
+    epoch: AtomicU8,
 }
 
-impl<T> AddressMap<T> {
-    pub fn new(start: Address, end: Address) -> AddressMap<T> {
-        let len = end.diff(start) >> LOG_POINTER_SIZE;
+impl TraceMap {
+    pub fn new(
+        map: SideMetadata<1>,
+        block_epochs: SideMetadata<8>,
+        log_bytes_in_block: usize,
+    ) -> Self {
+        TraceMap {
+            map,
+            block_epochs,
+            log_bytes_in_block,
+            epoch: AtomicU8::new(FIRST_EPOCH),
+        }
+    }
 
-        // TODO: This should be a regular Vec
-        let layout = Layout::array::<T>(len).unwrap();
+    /// Unmarks every object, before a trace starts from scratch. Must only be called while nothing
+    /// is being marked.
+    pub fn unmark_all(&self) {
+        let next = self.epoch.load(Ordering::Relaxed) + 1;
+        if next < CLEARING {
+            self.epoch.store(next, Ordering::SeqCst);
+            return;
+        }
 
-        let ptr = unsafe { System.alloc_zeroed(layout) as *mut T };
+        self.map.clear_all();
+        self.block_epochs.clear_all();
+        self.epoch.store(FIRST_EPOCH, Ordering::SeqCst);
+    }
 
-        AddressMap {
-            start,
-            end,
-            ptr,
-            layout,
-        }
+    /// Resets the entries for every word in `start..end`. Used when the memory is freed, so objects
+    /// allocated there later do not inherit a mark.
+    pub fn clear(&self, start: Address, end: Address) {
+        self.map.clear_range(start, end);
     }
 
+    /// Checks if the bits of the block containing `addr` belong to the current epoch.
     #[inline(always)]
-    pub fn set(&self, addr: Address, value: T) {
-        let index = (addr.diff(self.start) >> LOG_POINTER_SIZE) as isize;
-        unsafe { *self.ptr.offset(index) = value };
+    fn is_current(&self, addr: Address) -> bool {
+        let current = self.block_epochs.load(addr) == self.epoch.load(Ordering::Relaxed);
+        // pairs with the fence in `clear_block`, so the cleared bits are seen
+        fence(Ordering::Acquire);
+        current
     }
-}
 
-impl<T: Copy> AddressMap<T> {
+    /// Moves the block containing `addr` to the current epoch before something in it is marked.
     #[inline(always)]
-    pub fn get(&self, addr: Address) -> T {
-        debug_assert!(addr < self.end);
-
-        let index = (addr.diff(self.start) >> LOG_POINTER_SIZE) as isize;
-        unsafe { *self.ptr.offset(index) }
+    fn claim_block(&self, addr: Address) {
+        if !self.is_current(addr) {
+            self.clear_block(addr);
+        }
     }
-}
 
-impl<T> Drop for AddressMap<T> {
-    fn drop(&mut self) {
-        unsafe {
-            System.dealloc(self.ptr as *mut u8, self.layout);
+    /// Clears the bits of the block containing `addr`, left from an older epoch. Of several threads
+    /// marking in the block at once, one clears it and the others wait for it.
+    #[cold]
+    fn clear_block(&self, addr: Address) {
+        let epoch = self.epoch.load(Ordering::Relaxed);
+        loop {
+            let claim = self
+                .block_epochs
+                .fetch_update(addr, |x| (x != epoch && x != CLEARING).then_some(CLEARING));
+            match claim {
+                Ok(_) => {
+                    let block_size = 1 << self.log_bytes_in_block;
+                    let start = addr.align_down(block_size);
+                    self.map.clear_range(start, start.plus(block_size));
+                    fence(Ordering::Release);
+                    self.block_epochs.store(addr, epoch);
+                    return;
+                }
+                Err(x) if x == epoch => {
+                    fence(Ordering::Acquire);
+                    return;
+                }
+                Err(_) => spin_loop(),
+            }
         }
     }
-}
-
-pub struct SafeAddressMap<T> {
-    offset: usize,
-    addresses: Vec<T>,
-}
 
-impl<T> SafeAddressMap<T> {
-    pub fn new_of<F: FnMut() -> T>(start: usize, end: usize, f: F) -> Self {
-        let ptr_size = size_of::<*mut ()>();
-        let len = ((end - start) + ptr_size - 1) / ptr_size;
+    #[inline(always)]
+    pub fn is_traced<T>(&self, ptr: *const T) -> bool {
+        let addr = Address::from_ptr(ptr);
+        self.is_current(addr) && self.map.load(addr) != 0
+    }
 
-        let mut addresses = Vec::with_capacity(len);
-        addresses.resize_with(len, f);
+    #[inline(always)]
+    pub fn is_untraced_and_valid<T>(&self, ptr: *const T) -> bool {
+        let addr = Address::from_ptr(ptr);
+        self.map.contains(addr) && !self.is_traced(ptr)
+    }
 
-        SafeAddressMap {
-            offset: start,
-            addresses,
+    /// Marks `ptr` as traced, and returns whether this call did so. Of several threads reaching the
+    /// same object, exactly one claims it. Pointers outside the space are never claimed.
+    #[inline(always)]
+    pub fn try_mark<T>(&self, ptr: *const T) -> bool {
+        let addr = Address::from_ptr(ptr);
+        if !self.map.contains(addr) {
+            return false;
         }
+
+        self.claim_block(addr);
+        self.map.fetch_or(addr, 1) == 0
     }
 
     #[inline(always)]
-    pub fn get(&self, index: usize) -> Option<&T> {
-        let normalized_index = index.overflowing_sub(self.offset).0 / size_of::<*mut ()>();
-        self.addresses.get(normalized_index)
+    pub fn mark_as_traced<T>(&self, ptr: *const T) {
+        let addr = Address::from_ptr(ptr);
+        self.claim_block(addr);
+        self.map.fetch_or(addr, 1);
     }
 }
 
-impl<T: Default> SafeAddressMap<T> {
-    pub fn new(start: usize, end: usize) -> Self {
-        Self::new_of(start, end, T::default)
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{SideMetadataSpec, SideMetadataTables};
 
-impl<T> Index<usize> for SafeAddressMap<T> {
-    type Output = T;
+    const LOG_BYTES_IN_BLOCK: usize = 12;
+    const START: Address = Address(0x1000_0000);
+    const END: Address = Address(START.0 + (4 << LOG_BYTES_IN_BLOCK));
 
-    fn index(&self, index: usize) -> &Self::Output {
-        &self.addresses[(index - self.offset) / size_of::<*mut ()>()]
+    const MARKS: SideMetadataSpec = SideMetadataSpec::per_word("marks", 1);
+    const EPOCHS: SideMetadataSpec = SideMetadataSpec::new("epochs", 8, LOG_BYTES_IN_BLOCK);
+
+    fn trace_map(tables: &SideMetadataTables) -> TraceMap {
+        unsafe {
+            TraceMap::new(
+                tables.table(&MARKS),
+                tables.table(&EPOCHS),
+                LOG_BYTES_IN_BLOCK,
+            )
+        }
     }
-}
 
-impl<T> IndexMut<usize> for SafeAddressMap<T> {
-    fn index_mut(&mut self, index: usize) -> &mut Self::Output {
-        &mut self.addresses[(index - self.offset) / size_of::<*mut ()>()]
+    fn ptr(block: usize, word: usize) -> *const usize {
+        START
+            .plus((block << LOG_BYTES_IN_BLOCK) + word * 8)
+            .to_ptr()
     }
-}
 
-pub struct TraceMap {
-    map: SafeAddressMap<AtomicU8>,
-    mark_state: Cell<u8>,
-}
+    #[test]
+    fn objects_are_marked_once() {
+        let tables = SideMetadataTables::new(START, END, &[MARKS, EPOCHS]);
+        let map = trace_map(&tables);
 
-impl TraceMap {
-    pub fn new(start: usize, end: usize) -> Self {
-        TraceMap {
-            map: SafeAddressMap::new(start, end),
-            mark_state: Cell::new(0),
-        }
+        assert!(map.is_untraced_and_valid(ptr(1, 3)));
+        assert!(map.try_mark(ptr(1, 3)));
+        assert!(!map.try_mark(ptr(1, 3)));
+        assert!(map.is_traced(ptr(1, 3)));
+        assert!(!map.is_traced(ptr(1, 4)));
+        assert!(!map.is_untraced_and_valid(ptr(1, 3)));
+
+        // outside the space
+        assert!(!map.try_mark(END.to_ptr::<usize>()));
+        assert!(!map.is_untraced_and_valid(END.to_ptr::<usize>()));
     }
 
-    pub fn flip_mark_state(&self) {
-        self.mark_state.set(self.mark_state.get() ^ 1);
+    #[test]
+    fn unmarking_leaves_stale_bits_behind_until_the_block_is_marked_again() {
+        let tables = SideMetadataTables::new(START, END, &[MARKS, EPOCHS]);
+        let map = trace_map(&tables);
+        map.mark_as_traced(ptr(0, 0));
+        map.mark_as_traced(ptr(0, 511));
+        map.mark_as_traced(ptr(2, 7));
+
+        map.unmark_all();
+        assert!(!map.is_traced(ptr(0, 0)));
+        assert!(!map.is_traced(ptr(0, 511)));
+        assert!(!map.is_traced(ptr(2, 7)));
+
+        // marking in block 0 clears the bits it had, and no other block's
+        assert!(map.try_mark(ptr(0, 1)));
+        assert!(!map.is_traced(ptr(0, 0)));
+        assert!(!map.is_traced(ptr(0, 511)));
+        assert!(map.try_mark(ptr(0, 511)));
+        assert!(map.try_mark(ptr(2, 7)));
     }
 
-    #[inline(always)]
-    pub fn is_traced<T>(&self, ptr: *const T) -> bool {
-        self.map[ptr as usize].load(Ordering::Relaxed) == self.mark_state.get()
+    #[test]
+    fn clear_resets_a_range() {
+        let tables = SideMetadataTables::new(START, END, &[MARKS, EPOCHS]);
+        let map = trace_map(&tables);
+        for word in 0..16 {
+            map.mark_as_traced(ptr(3, word));
+        }
+
+        map.clear(Address::from_ptr(ptr(3, 4)), Address::from_ptr(ptr(3, 12)));
+        for word in 0..16 {
+            assert_eq!(map.is_traced(ptr(3, word)), !(4..12).contains(&word));
+        }
     }
 
-    #[inline(always)]
-    pub fn is_untraced_and_valid<T>(&self, ptr: *const T) -> bool {
-        match self.map.get(ptr as usize) {
-            Some(v) => v.load(Ordering::Relaxed) != self.mark_state.get(),
-            None => false,
+    #[test]
+    fn epochs_wrap_around_without_reviving_marks() {
+        let tables = SideMetadataTables::new(START, END, &[MARKS, EPOCHS]);
+        let map = trace_map(&tables);
+        // block 0 is only marked once, so its epoch is left far behind while block 1 keeps up
+        map.mark_as_traced(ptr(0, 9));
+
+        for round in 0..3 * CLEARING as usize {
+            map.unmark_all();
+            assert!(!map.is_traced(ptr(0, 9)), "round {}", round);
+            assert!(!map.is_traced(ptr(1, 9)), "round {}", round);
+            assert!(map.try_mark(ptr(1, 9)), "round {}", round);
+            assert!(!map.try_mark(ptr(1, 9)), "round {}", round);
         }
     }
 
-    #[inline(always)]
-    pub fn mark_as_traced<T>(&self, ptr: *const T) {
-        self.map[ptr as usize].store(self.mark_state.get(), Ordering::Relaxed)
+    #[test]
+    fn threads_marking_a_stale_block_at_once_keep_their_marks() {
+        let tables = SideMetadataTables::new(START, END, &[MARKS, EPOCHS]);
+        let map = trace_map(&tables);
+        for round in 0..50 {
+            for word in 0..512 {
+                map.mark_as_traced(ptr(0, word));
+            }
+            map.unmark_all();
+
+            // every thread may be the one to clear the block, and none may lose a mark to it
+            std::thread::scope(|scope| {
+                for thread in 0..8 {
+                    let map = &map;
+                    scope.spawn(move || {
+                        for word in (thread..256).step_by(8) {
+                            assert!(map.try_mark(ptr(0, word)), "round {}", round);
+                        }
+                    });
+                }
+            });
+            for word in 0..512 {
+                assert_eq!(map.is_traced(ptr(0, word)), word < 256, "round {}", round);
+            }
+        }
     }
 }
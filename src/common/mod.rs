@@ -2,9 +2,13 @@ use std::fmt;
 use std::ptr::null;
 
 mod address_map;
-pub use self::address_map::{AddressMap, SafeAddressMap, TraceMap};
+mod side_metadata;
+pub use self::address_map::TraceMap;
+pub use self::side_metadata::{SideMetadata, SideMetadataSpec, SideMetadataTables};
 
-const LOG_POINTER_SIZE: usize = 3;
+// objects are laid out in words of the target, so the side metadata kept per word follows its
+// pointer size rather than being chosen per heap
+const LOG_POINTER_SIZE: usize = std::mem::size_of::<*mut ()>().trailing_zeros() as usize;
 
 #[repr(transparent)]
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -16,6 +20,11 @@ impl Address {
         Address(self.0 + bytes)
     }
 
+    #[inline(always)]
+    pub fn minus(&self, bytes: usize) -> Self {
+        Address(self.0 - bytes)
+    }
+
     #[inline(always)]
     pub fn diff(&self, another: Address) -> usize {
         debug_assert!(
@@ -30,6 +39,11 @@ impl Address {
         Address((self.0 + align - 1) & !(align - 1))
     }
 
+    #[inline(always)]
+    pub fn align_down(&self, align: usize) -> Address {
+        Address(self.0 & !(align - 1))
+    }
+
     #[inline(always)]
     pub unsafe fn to_object_reference(self) -> ObjectReference {
         ObjectReference(self.0)
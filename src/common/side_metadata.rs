@@ -0,0 +1,486 @@
+use memmap2::{MmapMut, MmapOptions};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::common::Address;
+use crate::common::LOG_POINTER_SIZE;
+
+/// Describes one side metadata table of a space: a field of `bits` bits (1, 2, 4 or 8) for every
+/// region of `1 << log_region` bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SideMetadataSpec {
+    pub name: &'static str,
+    pub bits: usize,
+    pub log_region: usize,
+}
+
+impl SideMetadataSpec {
+    pub const fn new(name: &'static str, bits: usize, log_region: usize) -> SideMetadataSpec {
+        SideMetadataSpec {
+            name,
+            bits,
+            log_region,
+        }
+    }
+
+    /// A table with a field for every word of the space.
+    pub const fn per_word(name: &'static str, bits: usize) -> SideMetadataSpec {
+        SideMetadataSpec::new(name, bits, LOG_POINTER_SIZE)
+    }
+
+    /// The bytes taken by the table for `start..end`, rounded up to whole chunks.
+    fn size(&self, start: Address, end: Address) -> usize {
+        let bits = (end.diff(start) >> self.log_region) * self.bits;
+        bits.div_ceil(BITS_IN_CHUNK) * (BITS_IN_CHUNK / 8)
+    }
+}
+
+/// Every side metadata table of a space, laid out one after the other in a single zeroed mapping.
+/// Pages of a table are only backed by memory once they are written to, so tables for features
+/// which are never enabled cost nothing but address space.
+pub struct SideMetadataTables {
+    start: Address,
+    end: Address,
+    specs: Vec<(SideMetadataSpec, usize)>,
+    mmap: MmapMut,
+}
+
+impl SideMetadataTables {
+    pub fn new(start: Address, end: Address, specs: &[SideMetadataSpec]) -> SideMetadataTables {
+        let mut offset = 0;
+        let mut layout = Vec::with_capacity(specs.len());
+        for spec in specs {
+            assert!(
+                spec.bits.is_power_of_two() && spec.bits <= 8,
+                "side metadata fields are 1, 2, 4 or 8 bits wide"
+            );
+            assert!(
+                layout
+                    .iter()
+                    .all(|(x, _): &(SideMetadataSpec, usize)| x.name != spec.name),
+                "side metadata {} is declared twice",
+                spec.name
+            );
+            layout.push((*spec, offset));
+            offset += spec.size(start, end);
+        }
+
+        let mmap = MmapOptions::new()
+            .len(offset.max(1))
+            .map_anon()
+            .expect("failed to map side metadata");
+
+        SideMetadataTables {
+            start,
+            end,
+            specs: layout,
+            mmap,
+        }
+    }
+
+    /// The table laid out for `spec`.
+    ///
+    /// # Safety
+    ///
+    /// The table points into these tables without borrowing them, so that it can be kept next to
+    /// them (and copied into mutators and GC threads). It must not be used once these tables are
+    /// dropped.
+    pub unsafe fn table<const BITS: usize>(&self, spec: &SideMetadataSpec) -> SideMetadata<BITS> {
+        let (spec, offset) = self
+            .specs
+            .iter()
+            .find(|(x, _)| x == spec)
+            .unwrap_or_else(|| panic!("side metadata {} is not declared", spec.name));
+        assert_eq!(
+            spec.bits, BITS,
+            "side metadata {} has a different width",
+            spec.name
+        );
+
+        SideMetadata {
+            start: self.start,
+            end: self.end,
+            log_region: spec.log_region,
+            chunks: unsafe { self.mmap.as_ptr().add(*offset) } as *const AtomicU64,
+            len: spec.size(self.start, self.end) / (BITS_IN_CHUNK / 8),
+        }
+    }
+
+    /// The memory reserved for all the tables.
+    pub fn size_in_bytes(&self) -> usize {
+        self.mmap.len()
+    }
+}
+
+/// One side metadata table, as laid out by `SideMetadataTables`: a field of `BITS` bits for every
+/// region of the space. Fields are packed into atomic 64-bit chunks, so a mark bitmap costs one bit
+/// per word rather than a byte, and every field can be read and updated from any thread. The width
+/// is a type parameter so the tracing loop does not pay for working it out.
+///
+/// Accessors check that the address is in the space in debug builds only. Fast paths which can not
+/// afford even that go through `as_mut_ptr`.
+#[derive(Copy, Clone)]
+pub struct SideMetadata<const BITS: usize> {
+    start: Address,
+    end: Address,
+    log_region: usize,
+    chunks: *const AtomicU64,
+    len: usize,
+}
+
+// the chunks are only ever accessed atomically, and they stay mapped for as long as the table may
+// be used (see `SideMetadataTables::table`), so tables can be shared between threads
+unsafe impl<const BITS: usize> Send for SideMetadata<BITS> {}
+unsafe impl<const BITS: usize> Sync for SideMetadata<BITS> {}
+
+const LOG_BITS_IN_CHUNK: usize = 6;
+const BITS_IN_CHUNK: usize = 1 << LOG_BITS_IN_CHUNK;
+
+impl<const BITS: usize> SideMetadata<BITS> {
+    const LOG_BITS: usize = BITS.trailing_zeros() as usize;
+    const MASK: u64 = (1 << BITS) - 1;
+
+    #[inline(always)]
+    pub fn contains(&self, addr: Address) -> bool {
+        // addresses below the start wrap around to large offsets
+        addr.as_usize().wrapping_sub(self.start.as_usize()) < self.end.diff(self.start)
+    }
+
+    /// The first bit of the field of the region containing `addr`, counted from the start of the
+    /// table.
+    #[inline(always)]
+    fn bit(&self, addr: Address) -> usize {
+        debug_assert!(self.contains(addr), "{} is not in the space", addr);
+
+        (addr.diff(self.start) >> self.log_region) << Self::LOG_BITS
+    }
+
+    #[inline(always)]
+    fn chunk(&self, bit: usize) -> &AtomicU64 {
+        debug_assert!(bit >> LOG_BITS_IN_CHUNK < self.len);
+        unsafe { &*self.chunks.add(bit >> LOG_BITS_IN_CHUNK) }
+    }
+
+    /// Byte-wide fields are accessed as bytes, without disturbing their neighbours.
+    #[inline(always)]
+    fn byte(&self, addr: Address) -> &AtomicU8 {
+        unsafe { AtomicU8::from_ptr(self.as_mut_ptr().add(self.bit(addr) >> 3)) }
+    }
+
+    #[inline(always)]
+    pub fn load(&self, addr: Address) -> u8 {
+        if BITS == 8 {
+            return self.byte(addr).load(Ordering::Relaxed);
+        }
+
+        let bit = self.bit(addr);
+        let chunk = self.chunk(bit).load(Ordering::Relaxed);
+        ((chunk >> (bit % BITS_IN_CHUNK)) & Self::MASK) as u8
+    }
+
+    #[inline(always)]
+    pub fn store(&self, addr: Address, value: u8) {
+        if BITS == 8 {
+            return self.byte(addr).store(value, Ordering::Relaxed);
+        }
+
+        let _ = self.fetch_update(addr, |_| Some(value));
+    }
+
+    /// Updates the field of `addr` with `f` until no other thread gets in between, like
+    /// `AtomicU8::fetch_update`.
+    #[inline(always)]
+    pub fn fetch_update<F: FnMut(u8) -> Option<u8>>(
+        &self,
+        addr: Address,
+        mut f: F,
+    ) -> Result<u8, u8> {
+        if BITS == 8 {
+            return self
+                .byte(addr)
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, f);
+        }
+
+        let bit = self.bit(addr);
+        let shift = bit % BITS_IN_CHUNK;
+        self.chunk(bit)
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |x| {
+                let value = f(((x >> shift) & Self::MASK) as u8)? as u64;
+                debug_assert!(
+                    value & !Self::MASK == 0,
+                    "value does not fit into the field"
+                );
+                Some(x & !(Self::MASK << shift) | value << shift)
+            })
+            .map(|x| ((x >> shift) & Self::MASK) as u8)
+            .map_err(|x| ((x >> shift) & Self::MASK) as u8)
+    }
+
+    /// Stores `value` into the field of `addr`, and returns the field as it was before.
+    #[inline(always)]
+    pub fn swap(&self, addr: Address, value: u8) -> u8 {
+        if BITS == 8 {
+            return self.byte(addr).swap(value, Ordering::Relaxed);
+        }
+
+        match self.fetch_update(addr, |_| Some(value)) {
+            Ok(x) | Err(x) => x,
+        }
+    }
+
+    /// Sets the bits of `value` in the field of `addr`, and returns the field as it was before.
+    #[inline(always)]
+    pub fn fetch_or(&self, addr: Address, value: u8) -> u8 {
+        if BITS == 8 {
+            return self.byte(addr).fetch_or(value, Ordering::Relaxed);
+        }
+
+        debug_assert!(
+            value as u64 & !Self::MASK == 0,
+            "value does not fit into the field"
+        );
+        let bit = self.bit(addr);
+        let shift = bit % BITS_IN_CHUNK;
+        let old = self
+            .chunk(bit)
+            .fetch_or((value as u64) << shift, Ordering::Relaxed);
+        ((old >> shift) & Self::MASK) as u8
+    }
+
+    /// Keeps only the bits of `value` in the field of `addr`, and returns the field as it was
+    /// before.
+    #[inline(always)]
+    pub fn fetch_and(&self, addr: Address, value: u8) -> u8 {
+        if BITS == 8 {
+            return self.byte(addr).fetch_and(value, Ordering::Relaxed);
+        }
+
+        let bit = self.bit(addr);
+        let shift = bit % BITS_IN_CHUNK;
+        let mask = !((!value as u64 & Self::MASK) << shift);
+        let old = self.chunk(bit).fetch_and(mask, Ordering::Relaxed);
+        ((old >> shift) & Self::MASK) as u8
+    }
+
+    /// Clears the fields of every region in `start..end`. Chunks which are already clear are not
+    /// written to, so pages of the table which were never set stay unbacked.
+    pub fn clear_range(&self, start: Address, end: Address) {
+        debug_assert!(start <= end && end <= self.end);
+        if start == end {
+            return;
+        }
+
+        let mut bit = self.bit(start);
+        let end_bit = (end.diff(self.start) >> self.log_region) << Self::LOG_BITS;
+
+        while bit < end_bit {
+            let shift = bit % BITS_IN_CHUNK;
+            let len = (BITS_IN_CHUNK - shift).min(end_bit - bit);
+            let chunk = self.chunk(bit);
+            if len == BITS_IN_CHUNK {
+                if chunk.load(Ordering::Relaxed) != 0 {
+                    chunk.store(0, Ordering::Relaxed);
+                }
+            } else {
+                let mask = ((1 << len) - 1) << shift;
+                if chunk.load(Ordering::Relaxed) & mask != 0 {
+                    chunk.fetch_and(!mask, Ordering::Relaxed);
+                }
+            }
+            bit += len;
+        }
+    }
+
+    /// Clears every field. As with `clear_range`, chunks which are already clear are not written
+    /// to.
+    pub fn clear_all(&self) {
+        for i in 0..self.len {
+            let chunk = unsafe { &*self.chunks.add(i) };
+            if chunk.load(Ordering::Relaxed) != 0 {
+                chunk.store(0, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// The start of every region whose field is not zero, in increasing order. Chunks of zero
+    /// fields are skipped at once.
+    pub fn iter_nonzero(&self) -> impl Iterator<Item = Address> + '_ {
+        (0..self.len).flat_map(move |index| {
+            let mut chunk = unsafe { (*self.chunks.add(index)).load(Ordering::Relaxed) };
+            std::iter::from_fn(move || {
+                if chunk == 0 {
+                    return None;
+                }
+
+                let field = chunk.trailing_zeros() as usize >> Self::LOG_BITS;
+                chunk &= !(Self::MASK << (field << Self::LOG_BITS));
+                Some(((index << LOG_BITS_IN_CHUNK) >> Self::LOG_BITS) + field)
+            })
+            .map(move |region| self.start.plus(region << self.log_region))
+        })
+    }
+
+    /// The raw table, for fast paths which access it without going through this type (like
+    /// allocation, which is also written in C). The field of region `i` of the space is made of the
+    /// bits `i * BITS % 8..` of byte `i * BITS / 8` (chunks are little-endian, like the supported
+    /// targets).
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        self.chunks as *mut u8
+    }
+}
+
+impl SideMetadata<1> {
+    #[inline(always)]
+    pub fn set(&self, addr: Address) {
+        self.fetch_or(addr, 1);
+    }
+
+    #[inline(always)]
+    pub fn clear(&self, addr: Address) {
+        self.fetch_and(addr, 0);
+    }
+
+    #[inline(always)]
+    pub fn is_set(&self, addr: Address) -> bool {
+        self.load(addr) != 0
+    }
+
+    /// Clears the bit of `addr`, and returns whether it was set.
+    #[inline(always)]
+    pub fn test_and_clear(&self, addr: Address) -> bool {
+        self.fetch_and(addr, 0) != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the tables are only addressed, never the space, so any aligned range will do
+    const START: Address = Address(0x1000_0000);
+    const WORDS: usize = 1000;
+    const END: Address = Address(START.0 + (WORDS << LOG_POINTER_SIZE));
+
+    const SPECS: [SideMetadataSpec; 4] = [
+        SideMetadataSpec::per_word("1 bit", 1),
+        SideMetadataSpec::per_word("2 bits", 2),
+        SideMetadataSpec::per_word("4 bits", 4),
+        SideMetadataSpec::per_word("8 bits", 8),
+    ];
+
+    fn word(i: usize) -> Address {
+        START.plus(i << LOG_POINTER_SIZE)
+    }
+
+    // every field gets a different value, which does not fit into a narrower one
+    fn check_packing<const BITS: usize>(tables: &SideMetadataTables, spec: &SideMetadataSpec) {
+        let table = unsafe { tables.table::<BITS>(spec) };
+        let mask = ((1u16 << BITS) - 1) as u8;
+        let value = |i: usize| (i * 7 + 1) as u8 & mask;
+
+        for i in 0..WORDS {
+            table.store(word(i), value(i));
+        }
+        for i in 0..WORDS {
+            assert_eq!(
+                table.load(word(i)),
+                value(i),
+                "field {} of {} bits",
+                i,
+                BITS
+            );
+        }
+
+        assert_eq!(table.swap(word(5), 0), value(5));
+        assert_eq!(table.load(word(5)), 0);
+        assert_eq!(table.load(word(4)), value(4));
+        assert_eq!(table.load(word(6)), value(6));
+
+        assert_eq!(table.fetch_or(word(5), mask), 0);
+        assert_eq!(table.fetch_and(word(5), 1), mask);
+        assert_eq!(table.load(word(5)), 1);
+        assert_eq!(table.load(word(4)), value(4));
+        assert_eq!(table.load(word(6)), value(6));
+
+        table.clear_all();
+        assert!((0..WORDS).all(|i| table.load(word(i)) == 0));
+    }
+
+    #[test]
+    fn fields_are_packed_without_overlapping() {
+        let tables = SideMetadataTables::new(START, END, &SPECS);
+        check_packing::<1>(&tables, &SPECS[0]);
+        check_packing::<2>(&tables, &SPECS[1]);
+        check_packing::<4>(&tables, &SPECS[2]);
+        check_packing::<8>(&tables, &SPECS[3]);
+    }
+
+    #[test]
+    fn tables_do_not_overlap() {
+        let tables = SideMetadataTables::new(START, END, &SPECS);
+        let bits = unsafe { tables.table::<1>(&SPECS[0]) };
+        let bytes = unsafe { tables.table::<8>(&SPECS[3]) };
+
+        for i in 0..WORDS {
+            bytes.store(word(i), u8::MAX);
+        }
+        assert!(bits.iter_nonzero().next().is_none());
+
+        // the last word lies in a partial chunk of the bit table
+        bits.set(word(WORDS - 1));
+        assert!((0..WORDS).all(|i| bytes.load(word(i)) == u8::MAX));
+    }
+
+    #[test]
+    fn clear_range_stops_at_the_ends() {
+        let tables = SideMetadataTables::new(START, END, &SPECS);
+        for (start, end) in [(0, 64), (3, 61), (60, 130), (63, 64), (64, 128), (1, WORDS)] {
+            let table = unsafe { tables.table::<1>(&SPECS[0]) };
+            for i in 0..WORDS {
+                table.set(word(i));
+            }
+
+            table.clear_range(word(start), word(end).min(END));
+            for i in 0..WORDS {
+                assert_eq!(
+                    table.is_set(word(i)),
+                    !(start..end).contains(&i),
+                    "bit {} after clearing {}..{}",
+                    i,
+                    start,
+                    end
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn iter_nonzero_finds_every_field_in_order() {
+        let tables = SideMetadataTables::new(START, END, &SPECS);
+        let bits = unsafe { tables.table::<1>(&SPECS[0]) };
+        let nibbles = unsafe { tables.table::<4>(&SPECS[2]) };
+
+        let set = [0, 1, 63, 64, 65, 127, 128, 500, WORDS - 1];
+        for &i in set.iter() {
+            bits.set(word(i));
+            nibbles.store(word(i), 8);
+        }
+
+        let expected: Vec<Address> = set.iter().map(|&i| word(i)).collect();
+        assert_eq!(bits.iter_nonzero().collect::<Vec<_>>(), expected);
+        assert_eq!(nibbles.iter_nonzero().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn fields_cover_regions() {
+        // a byte for every 4 words, as for per-block tables
+        let spec = SideMetadataSpec::new("per 32 bytes", 8, 5);
+        let tables = SideMetadataTables::new(START, END, &[spec]);
+        let table = unsafe { tables.table::<8>(&spec) };
+
+        table.store(word(4), 3);
+        assert_eq!(table.load(word(3)), 0);
+        assert!((4..8).all(|i| table.load(word(i)) == 3));
+        assert_eq!(table.load(word(8)), 0);
+        assert_eq!(table.iter_nonzero().collect::<Vec<_>>(), vec![word(4)]);
+    }
+}
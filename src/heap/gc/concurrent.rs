@@ -0,0 +1,91 @@
+use super::{collect, is_valid_object, start_trace, trigger_gc};
+use crate::common::ObjectReference;
+use crate::heap::immix::ImmixSpace;
+
+use log::trace;
+use std::sync::Arc;
+use std::thread;
+
+/// Runs one of the two pauses of a concurrent marking cycle (see `ConcurrentMarking`). The first
+/// pause advances the mark state and starts a thread tracing from `roots` while mutators run (or,
+/// with incremental marking, leaves them to the slices of `mark_slice`). The second one traces
+/// whatever the barrier recorded since, and sweeps. Roots found by the second pause are not
+/// needed: everything they refer to was either reachable when marking started, or allocated
+/// since.
+///
+/// If the mutators run out of blocks before marking can start, or the sweep leaves the space
+/// exhausted, the whole heap is collected while the world is stopped instead.
+pub fn collect_concurrent(
+    immix_space: &Arc<ImmixSpace>,
+    roots: Vec<ObjectReference>,
+    precise_roots: Vec<ObjectReference>,
+) {
+    if !immix_space.concurrent.is_marking() {
+        if immix_space.is_out_of_blocks() {
+            trace!("space is exhausted, collecting the full heap without marking concurrently");
+            collect(immix_space, roots, precise_roots, true);
+            return;
+        }
+
+        trace!("initial pause, marking starts");
+        immix_space.trace_map.unmark_all();
+
+        let mut work: Vec<ObjectReference> = roots.into_iter().chain(precise_roots).collect();
+        if immix_space.concurrent.budget().is_some() {
+            immix_space.concurrent.start(None);
+            immix_space
+                .concurrent
+                .try_lock_grey()
+                .unwrap()
+                .append(&mut work);
+            return;
+        }
+
+        let space = immix_space.clone();
+        let thread = thread::spawn(move || {
+            while !work.is_empty() {
+                start_trace(&mut work, space.clone());
+                work = space.take_satb_entries();
+            }
+
+            // the rest of the recorded references are traced in the final pause
+            trace!("concurrent marking done");
+            trigger_gc();
+        });
+        immix_space.concurrent.start(Some(thread));
+        return;
+    }
+
+    trace!("final pause");
+    immix_space.concurrent.finish();
+
+    // mutators flushed their buffers before stopping
+    let mut work = immix_space.concurrent.take_grey();
+    work.append(&mut immix_space.take_satb_entries());
+    if cfg!(debug_assertions) {
+        println!(
+            "marking work = {} objects left for the final pause",
+            work.len()
+        );
+    }
+    start_trace(&mut work, immix_space.clone());
+    trace!("trace done");
+
+    if immix_space.sweep(true) {
+        trace!("concurrent marking left the space exhausted, collecting the full heap");
+        // ambiguous roots found by this pause may refer to objects which were already dead when
+        // marking started, and have just been swept
+        let roots = roots
+            .into_iter()
+            .filter(|x| {
+                is_valid_object(
+                    x.to_address(),
+                    immix_space.start(),
+                    immix_space.end(),
+                    &immix_space.object_starts,
+                )
+            })
+            .collect();
+        collect(immix_space, roots, precise_roots, true);
+    }
+}
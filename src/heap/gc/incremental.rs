@@ -0,0 +1,76 @@
+use super::trigger_gc;
+use crate::common::ObjectReference;
+use crate::heap::immix::{ImmixSpace, MarkingBudget};
+use crate::objectmodel;
+
+use log::trace;
+use std::time::Instant;
+
+// objects scanned between two looks at the clock, for time budgets
+const TIME_CHECK_INTERVAL: usize = 64;
+
+/// Runs one slice of incremental marking on the calling mutator, if a cycle is in progress: scans
+/// objects until the budget runs out, then returns. The slice which leaves nothing to scan
+/// requests the final pause (see `collect_concurrent`), which also takes the references still
+/// buffered by other mutators.
+pub fn mark_slice(immix_space: &ImmixSpace) {
+    let budget = match immix_space.concurrent.budget() {
+        Some(budget) if immix_space.concurrent.is_marking() => budget,
+        _ => return,
+    };
+    let mut grey = match immix_space.concurrent.try_lock_grey() {
+        Some(grey) => grey,
+        None => return,
+    };
+    grey.append(&mut immix_space.take_satb_entries());
+
+    let start = Instant::now();
+    let mut scanned = 0;
+    while let Some(obj) = grey.pop() {
+        scan_object(immix_space, obj, &mut grey);
+        scanned += 1;
+
+        let used_up = match budget {
+            MarkingBudget::Time(limit) => {
+                scanned % TIME_CHECK_INTERVAL == 0 && start.elapsed() >= limit
+            }
+            MarkingBudget::Work(limit) => scanned >= limit,
+        };
+        if used_up {
+            break;
+        }
+    }
+
+    trace!(
+        "marking slice scanned {} objects, {} left",
+        scanned,
+        grey.len()
+    );
+    if grey.is_empty() {
+        trigger_gc();
+    }
+}
+
+/// Marks `obj` and its line live, and queues the objects it refers to which are not marked yet.
+#[inline(always)]
+fn scan_object(immix_space: &ImmixSpace, obj: ObjectReference, grey: &mut Vec<ObjectReference>) {
+    let trace_map = &immix_space.trace_map;
+    let (alloc_map, space_start) = (immix_space.alloc_map.as_mut_ptr(), immix_space.start());
+    let object_starts = immix_space.object_starts.as_mut_ptr();
+
+    let addr = obj.to_address();
+    // edges are marked when queued, but roots and recorded references are queued as they are
+    trace_map.mark_as_traced(addr.to_ptr::<()>());
+    immix_space.mark_object_lines(obj);
+
+    let encode = unsafe { objectmodel::get_ref_byte(alloc_map, space_start, obj) };
+    for slot in objectmodel::ref_slots(encode, obj) {
+        let value = unsafe { *slot.to_ptr::<ObjectReference>() };
+        if trace_map.is_untraced_and_valid(value.to_address().to_ptr::<()>())
+            && unsafe { objectmodel::is_object_start(object_starts, space_start, value) }
+        {
+            trace_map.mark_as_traced(value.to_address().to_ptr::<()>());
+            grey.push(value);
+        }
+    }
+}
@@ -1,27 +1,52 @@
 use crate::heap::immix::ImmixMutatorLocal;
 use crate::heap::immix::ImmixSpace;
+use crate::heap::immix::WriteBarrier;
 use crate::heap::immix::MUTATORS;
 use crate::heap::immix::N_MUTATORS;
-use crate::objectmodel;
 use std::arch::asm;
 use std::mem::size_of;
 use std::ptr::null_mut;
 
-use crate::common::AddressMap;
+use crate::common::SideMetadata;
 use crate::common::{Address, ObjectReference};
 
 use lazy_static::lazy_static;
 use log::trace;
 use parking_lot::{Condvar, Mutex, RwLock};
-use std::sync::atomic::{AtomicIsize, AtomicPtr, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicIsize, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+mod concurrent;
+mod incremental;
 mod multi_thread_trace;
-pub use multi_thread_trace::start_trace;
+mod retention;
+mod workers;
+pub use incremental::mark_slice;
+pub use multi_thread_trace::{evacuate_slots, start_trace};
+pub use retention::{
+    last_retention_report, retention_report_enabled, set_retention_report, RetentionReport,
+};
+pub use workers::GcWorkers;
 
 lazy_static! {
     static ref STW_COND: Arc<(Mutex<usize>, Condvar)> = Arc::new((Mutex::new(0), Condvar::new()));
     static ref ROOTS: RwLock<Vec<ObjectReference>> = RwLock::new(vec![]);
+    static ref PRECISE_ROOTS: RwLock<Vec<ObjectReference>> = RwLock::new(vec![]);
+}
+
+/// Registers `obj` as a precise root. Unlike the roots found by scanning stacks, precise roots are
+/// known to be object references, and are kept until removed with `remove_root`.
+pub fn add_root(obj: ObjectReference) {
+    PRECISE_ROOTS.write().push(obj);
+}
+
+/// Removes one registration of `obj` added by `add_root`.
+pub fn remove_root(obj: ObjectReference) {
+    let mut roots = PRECISE_ROOTS.write();
+    if let Some(index) = roots.iter().position(|x| *x == obj) {
+        roots.swap_remove(index);
+    }
 }
 
 static CONTROLLER: AtomicIsize = AtomicIsize::new(NO_CONTROLLER);
@@ -57,7 +82,7 @@ fn immmix_get_stack_ptr() -> *mut () {
     ret
 }
 
-thread_local!(static LOW_WATER_MARK: AtomicPtr<()> = AtomicPtr::new(null_mut()));
+thread_local!(static LOW_WATER_MARK: AtomicPtr<()> = const { AtomicPtr::new(null_mut()) });
 
 pub extern "C" fn set_low_water_mark() {
     LOW_WATER_MARK.with(|f| f.store(immmix_get_stack_ptr(), Ordering::Relaxed));
@@ -68,12 +93,42 @@ fn get_low_water_mark() -> Address {
 }
 
 #[inline(always)]
-fn is_valid_object(addr: Address, start: Address, end: Address, live_map: &AddressMap<u8>) -> bool {
-    if addr >= end || addr < start {
+fn is_valid_object(
+    addr: Address,
+    start: Address,
+    end: Address,
+    object_starts: &SideMetadata<1>,
+) -> bool {
+    if addr >= end || addr < start || !addr.as_usize().is_multiple_of(size_of::<*mut ()>()) {
         return false;
     }
 
-    live_map.get(addr) & (1 << objectmodel::OBJ_START_BIT) != 0
+    object_starts.is_set(addr)
+}
+
+/// Checks an ambiguous word found while scanning roots. Words that point to an object (or inside a
+/// young one) are returned as roots, while words that only fall somewhere inside the space get
+/// their line blacklisted.
+#[inline(always)]
+fn scan_ambiguous_word(value: Address, immix_space: &ImmixSpace) -> Option<ObjectReference> {
+    if is_valid_object(
+        value,
+        immix_space.start(),
+        immix_space.end(),
+        &immix_space.object_starts,
+    ) {
+        return Some(unsafe { value.to_object_reference() });
+    }
+
+    if let Some(obj) = immix_space.find_young_object(value) {
+        return Some(obj);
+    }
+
+    if immix_space.addr_in_space(value) {
+        immix_space.blacklist.record(value);
+    }
+
+    None
 }
 
 fn stack_scan(immix_space: &ImmixSpace) -> Vec<ObjectReference> {
@@ -86,13 +141,8 @@ fn stack_scan(immix_space: &ImmixSpace) -> Vec<ObjectReference> {
     while cursor < low_water_mark {
         let value: Address = unsafe { *cursor.to_ptr::<Address>() };
 
-        if is_valid_object(
-            value,
-            immix_space.start(),
-            immix_space.end(),
-            &immix_space.alloc_map,
-        ) {
-            ret.push(unsafe { value.to_object_reference() });
+        if let Some(obj) = scan_ambiguous_word(value, immix_space) {
+            ret.push(obj);
         }
 
         cursor = cursor.plus(size_of::<*mut ()>());
@@ -114,13 +164,8 @@ fn stack_scan(immix_space: &ImmixSpace) -> Vec<ObjectReference> {
             asm!(concat!("mov {0}, ", stringify!($reg)), out(reg) $reg);
         };
         (@store $reg:ident) => {
-            if is_valid_object(
-                Address::from_ptr($reg),
-                immix_space.start(),
-                immix_space.end(),
-                &immix_space.alloc_map,
-            ) {
-                ret.push(Address::from_ptr($reg).to_object_reference());
+            if let Some(obj) = scan_ambiguous_word(Address::from_ptr($reg), immix_space) {
+                ret.push(obj);
             }
         };
     }
@@ -170,7 +215,7 @@ pub fn sync_barrier(mutator: &mut ImmixMutatorLocal) {
     mutator.prepare_for_gc();
 
     // scan its stack
-    let mut thread_roots = stack_scan(&*mutator.immix_space());
+    let mut thread_roots = stack_scan(&mutator.immix_space());
     ROOTS.write().append(&mut thread_roots);
 
     // user thread call back to prepare for gc
@@ -191,7 +236,7 @@ pub fn sync_barrier(mutator: &mut ImmixMutatorLocal) {
             // other threads should block
 
             // wait for all mutators to be blocked
-            let &(ref lock, ref cvar) = &*STW_COND.clone();
+            let (lock, cvar) = &*STW_COND.clone();
             let mut count = 0;
 
             trace!(
@@ -209,7 +254,9 @@ pub fn sync_barrier(mutator: &mut ImmixMutatorLocal) {
             trace!("everyone stopped, gc will start");
 
             // roots->trace->sweep
+            let start = Instant::now();
             gc(mutator.immix_space());
+            record_pause(start.elapsed());
 
             // mutators will resume
             CONTROLLER.store(NO_CONTROLLER, Ordering::SeqCst);
@@ -233,7 +280,7 @@ pub fn sync_barrier(mutator: &mut ImmixMutatorLocal) {
 fn block_current_thread(mutator: &mut ImmixMutatorLocal) {
     trace!("Mutator{:?} blocked", mutator.id());
 
-    let &(ref lock, ref cvar) = &*STW_COND.clone();
+    let (lock, cvar) = &*STW_COND.clone();
     let mut count = lock.lock();
     *count += 1;
 
@@ -252,22 +299,218 @@ pub fn gc_count() -> usize {
     GC_COUNT.load(Ordering::SeqCst)
 }
 
+// nanoseconds spent collecting while mutators were stopped, in total and in the longest pause
+static GC_PAUSE_NANOS: AtomicU64 = AtomicU64::new(0);
+static MAX_GC_PAUSE_NANOS: AtomicU64 = AtomicU64::new(0);
+
+fn record_pause(pause: Duration) {
+    let nanos = pause.as_nanos() as u64;
+    GC_PAUSE_NANOS.fetch_add(nanos, Ordering::SeqCst);
+    MAX_GC_PAUSE_NANOS.fetch_max(nanos, Ordering::SeqCst);
+}
+
+/// The time spent collecting with every mutator stopped, summed over all collections so far. Time
+/// spent waiting for mutators to reach a yieldpoint is not included.
+pub fn gc_pause_time() -> Duration {
+    Duration::from_nanos(GC_PAUSE_NANOS.load(Ordering::SeqCst))
+}
+
+/// The longest of the pauses counted by `gc_pause_time`.
+pub fn max_gc_pause() -> Duration {
+    Duration::from_nanos(MAX_GC_PAUSE_NANOS.load(Ordering::SeqCst))
+}
+
 fn gc(immix_space: Arc<ImmixSpace>) {
     GC_COUNT.fetch_add(1, Ordering::SeqCst);
 
     trace!("GC starts");
-
     // creates root deque
-    let roots: &mut Vec<ObjectReference> = &mut ROOTS.write();
+    let mut roots = std::mem::take(&mut *ROOTS.write());
+    if immix_space.finish_sweeping() {
+        forget_swept_roots(&mut roots, &immix_space);
+    }
+
+    // every mutator has scanned its roots, so the lines they blacklisted can take effect
+    immix_space.blacklist.advance();
+
+    let mut precise_roots = PRECISE_ROOTS.read().clone();
+    precise_roots.append(&mut immix_space.pinned_objects());
+
+    if immix_space.rc.is_enabled() {
+        let mut all_roots = roots.clone();
+        all_roots.extend(precise_roots.iter().copied());
+
+        let exhausted = immix_space.rc_collect(&all_roots);
+        let backup_trace = exhausted || immix_space.rc.is_backup_trace_due();
+        if backup_trace {
+            // cycles and stuck counts are only reclaimed by tracing, which recounts everything
+            trace!("backup trace");
+            immix_space.rc.reset();
+            immix_space
+                .remset
+                .clear(immix_space.start(), immix_space.end());
+            collect(&immix_space, roots, precise_roots, true);
+            immix_space.rc_count_roots(&all_roots);
+        }
+
+        immix_space.rc.finish_collection(backup_trace);
+        trace!("GC finishes");
+        return;
+    }
+
+    if immix_space.concurrent.is_enabled() {
+        concurrent::collect_concurrent(&immix_space, roots, precise_roots);
+        trace!("GC finishes");
+        return;
+    }
+
+    if immix_space.is_generational() {
+        collect_minor(&immix_space, roots.clone(), precise_roots.clone());
+
+        if immix_space.is_mature_space_low() {
+            // the next minor collection might not have anywhere to copy its survivors to
+            trace!("mature space is low, collecting the full heap");
+            collect(&immix_space, roots, precise_roots, true);
+            immix_space.clear_nursery_marks();
+        }
+
+        trace!("GC finishes");
+        return;
+    }
+
+    let full_heap = immix_space.sticky.is_full_heap_next();
+    if !collect(
+        &immix_space,
+        roots.clone(),
+        precise_roots.clone(),
+        full_heap,
+    ) {
+        // the nursery collection could not free a single block, so old objects have to go too
+        trace!("nursery collection left the space exhausted, collecting the full heap");
+        collect(&immix_space, roots, precise_roots, true);
+    }
+
+    immix_space.sticky.finish_collection(full_heap);
+    trace!("GC finishes");
+}
+
+/// Drops the ambiguous roots which referred to objects that were only found dead by the sweep
+/// finished after they were scanned. Their memory may be handed out again before they are traced.
+fn forget_swept_roots(roots: &mut Vec<ObjectReference>, immix_space: &ImmixSpace) {
+    roots.retain(|x| {
+        let addr = x.to_address();
+        immix_space.in_nursery(addr)
+            || is_valid_object(
+                addr,
+                immix_space.start(),
+                immix_space.end(),
+                &immix_space.object_starts,
+            )
+    });
+}
+
+/// Traces and sweeps the space once, either as a full-heap or as a nursery collection. Returns
+/// false if a nursery collection left the space exhausted.
+fn collect(
+    immix_space: &Arc<ImmixSpace>,
+    mut roots: Vec<ObjectReference>,
+    mut precise_roots: Vec<ObjectReference>,
+    full_heap: bool,
+) -> bool {
+    trace!(
+        "{} collection",
+        if full_heap { "full-heap" } else { "nursery" }
+    );
+    // reference counting sweeps before its backup traces
+    if immix_space.finish_sweeping() {
+        forget_swept_roots(&mut roots, immix_space);
+    }
+    let pinned: Vec<ObjectReference> = roots.iter().chain(precise_roots.iter()).copied().collect();
+
+    if full_heap {
+        // forget every mark, including the sticky ones of objects that survived nursery collections
+        immix_space.trace_map.unmark_all();
+
+        // roots are pinned, so choose defrag candidates now that we know where they are
+        immix_space.prepare_defrag(pinned.iter());
+
+        // every live object is traced anyway
+        immix_space.remset.take();
+    } else {
+        // old objects are not traced, so whatever the write barrier recorded acts as roots
+        precise_roots.append(&mut immix_space.remembered_roots());
+    }
 
     // mark & trace
-    start_trace(roots, immix_space.clone());
+    if retention_report_enabled() {
+        retention::trace_with_report(&mut roots, &mut precise_roots, immix_space.clone());
+    } else {
+        roots.append(&mut precise_roots);
+        let scanned = start_trace(&mut roots, immix_space.clone());
+        trace!("scanned {} objects", scanned);
+
+        // each object is claimed by exactly one GC thread, so a full trace scans every object it
+        // marks once. The count only sees marked objects in live lines, which leaves out those
+        // allocated while marking concurrently, and the lines of the nursery
+        if full_heap && !immix_space.concurrent.is_enabled() && !immix_space.is_generational() {
+            debug_assert_eq!(
+                scanned,
+                immix_space.traced_object_stats().0,
+                "objects were scanned more than once"
+            );
+        }
+    }
 
     trace!("trace done");
 
     // sweep
-    immix_space.sweep();
+    let exhausted = immix_space.sweep(full_heap);
+    if exhausted && full_heap {
+        // the space is exhausted, so slide everything together before reporting OOM
+        immix_space.compact(&pinned);
+        immix_space.ensure_usable_blocks();
+    }
 
-    immix_space.trace_map.flip_mark_state();
-    trace!("GC finishes");
+    full_heap || !exhausted
+}
+
+/// Copies the live objects out of the nursery into the mature space (GenImmix). Roots into the
+/// nursery can not be updated, so the objects they refer to stay where they are, and roots into the
+/// mature space are ignored: references from old objects to young ones are found through the
+/// remembered set instead.
+fn collect_minor(
+    immix_space: &Arc<ImmixSpace>,
+    roots: Vec<ObjectReference>,
+    precise_roots: Vec<ObjectReference>,
+) {
+    trace!("minor collection");
+    immix_space.set_minor_gc(true);
+
+    let mut roots: Vec<ObjectReference> = roots
+        .into_iter()
+        .chain(precise_roots)
+        .filter(|x| immix_space.in_nursery(x.to_address()))
+        .collect();
+    // mark the roots before anything is copied, so they are never reached as candidates
+    for root in roots.iter() {
+        immix_space
+            .trace_map
+            .mark_as_traced(root.to_address().to_ptr::<()>());
+    }
+
+    match immix_space.remset.barrier() {
+        WriteBarrier::Object | WriteBarrier::Card | WriteBarrier::SoftDirty => {
+            roots.append(&mut immix_space.remembered_roots())
+        }
+        WriteBarrier::Field => {
+            let slots = immix_space.remset.take();
+            roots.append(&mut evacuate_slots(slots, immix_space.clone()));
+        }
+    }
+
+    start_trace(&mut roots, immix_space.clone());
+    trace!("trace done");
+
+    immix_space.set_minor_gc(false);
+    immix_space.sweep_nursery();
 }
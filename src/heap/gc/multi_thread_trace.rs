@@ -1,79 +1,271 @@
+use crate::common::Address;
+use crate::heap::immix::CopyAllocator;
 use crate::{objectmodel, ImmixSpace, ObjectReference};
-use crossbeam::deque::Injector;
-use crossbeam::deque::{Steal, Worker};
+use crossbeam::deque::{Injector, Steal, Stealer, Worker};
+use parking_lot::{Condvar, Mutex};
+use std::collections::VecDeque;
 use std::hint::spin_loop;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{self, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::thread::JoinHandle;
 
+// objects a GC thread keeps in its own queue before sharing the rest through the injector
+const LOCAL_QUEUE_LIMIT: usize = 4096;
+
+/// Traces everything reachable from `work_stack` on the GC threads, and returns the number of
+/// objects scanned. Each object is scanned at most once, whichever edges reach it.
 #[inline(never)]
-pub fn start_trace(work_stack: &mut Vec<ObjectReference>, immix_space: Arc<ImmixSpace>) {
-    let injector = Arc::new(Injector::new());
-
-    let cpus = num_cpus::get();
-    let active_threads = Arc::new(AtomicUsize::new(cpus));
-
-    // Fill in initial injector items
-    work_stack.drain(..).for_each(|x| injector.push(x));
-
-    // Launch trace threads
-    let mut join_handles = Vec::with_capacity(cpus);
-    for _ in 0..cpus {
-        let injector_handle = injector.clone();
-        let active_threads_handle = active_threads.clone();
-        let immix_handle = immix_space.clone();
-        join_handles.push(thread::spawn(move || {
-            worker_batch_steal_trace(injector_handle, active_threads_handle, immix_handle)
-        }));
+pub fn start_trace(work_stack: &mut Vec<ObjectReference>, immix_space: Arc<ImmixSpace>) -> usize {
+    let n_workers = immix_space.gc_workers.n_workers();
+    let workers: Vec<Worker<ObjectReference>> =
+        (0..n_workers).map(|_| Worker::new_lifo()).collect();
+    let shared = Arc::new(SharedQueues {
+        injector: Injector::new(),
+        stealers: workers.iter().map(Worker::stealer).collect(),
+        termination: Termination::new(n_workers),
+    });
+    let workers: Vec<Mutex<Option<Worker<ObjectReference>>>> =
+        workers.into_iter().map(|x| Mutex::new(Some(x))).collect();
+
+    // Fill in initial injector items. Roots are scanned even if they are marked already (old
+    // objects in the remembered set are), so they are marked up front and no edge queues them again
+    work_stack.sort_unstable();
+    work_stack.dedup();
+    for root in work_stack.drain(..) {
+        immix_space
+            .trace_map
+            .mark_as_traced(root.to_address().to_ptr::<()>());
+        shared.injector.push(root);
     }
+    let scanned = Arc::new(AtomicUsize::new(0));
+
+    // Run the trace on the GC threads, and wait for all of them to finish
+    let immix_handle = immix_space.clone();
+    let scanned_handle = scanned.clone();
+    immix_space.gc_workers.run(move |id| {
+        let _abort = AbortOnPanic(&shared.termination);
+        let queue = TraceQueue {
+            id,
+            local: workers[id].lock().take().unwrap(),
+            shared: &shared,
+        };
+        let n = worker_batch_steal_trace(queue, immix_handle.clone());
+        scanned_handle.fetch_add(n, Ordering::Relaxed);
+    });
+
+    scanned.load(Ordering::Relaxed)
+}
+
+/// The queues shared by the GC threads of one trace.
+struct SharedQueues {
+    // roots, and objects which did not fit into the queue of the thread which found them
+    injector: Injector<ObjectReference>,
+    // the other end of the queue of every thread
+    stealers: Vec<Stealer<ObjectReference>>,
+    termination: Termination,
+}
 
-    // Wait for all threads to finish
-    join_handles
-        .into_iter()
-        .map(JoinHandle::join)
-        .for_each(Result::unwrap);
+/// Decides when a trace is over, which is when every GC thread has run out of work at once. Idle
+/// threads park until one which still has work shares some.
+struct Termination {
+    n_workers: usize,
+    idle: AtomicUsize,
+    done: Mutex<bool>,
+    wake_up: Condvar,
 }
 
-fn worker_batch_steal_trace(
-    injector: Arc<Injector<ObjectReference>>,
-    active_threads: Arc<AtomicUsize>,
-    immix_space: Arc<ImmixSpace>,
-) {
-    let worker = Worker::new_fifo();
+impl Termination {
+    fn new(n_workers: usize) -> Termination {
+        Termination {
+            n_workers,
+            idle: AtomicUsize::new(0),
+            done: Mutex::new(false),
+            wake_up: Condvar::new(),
+        }
+    }
+}
+
+/// Ends the trace if the GC thread holding it panics. The thread never goes idle, so the others
+/// would otherwise wait for it forever, rather than return and have the panic resumed (see
+/// `GcWorkers::run`).
+struct AbortOnPanic<'a>(&'a Termination);
+
+impl Drop for AbortOnPanic<'_> {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            *self.0.done.lock() = true;
+            self.0.wake_up.notify_all();
+        }
+    }
+}
+
+/// The queue of one GC thread. Objects are pushed to its own end first, overflow into the
+/// injector, and are stolen from the other threads once both are empty.
+struct TraceQueue<'a> {
+    id: usize,
+    local: Worker<ObjectReference>,
+    shared: &'a SharedQueues,
+}
+
+impl<'a> TraceQueue<'a> {
+    #[inline(always)]
+    fn push(&self, obj: ObjectReference) {
+        // a single object in the local queue is about to be popped again, so it is not worth
+        // waking anyone up for
+        let sharing = if self.local.len() < LOCAL_QUEUE_LIMIT {
+            self.local.push(obj);
+            self.local.len() > 1
+        } else {
+            self.shared.injector.push(obj);
+            true
+        };
+
+        // either a thread going idle sees the object (it counts itself idle first, see `pop_slow`),
+        // or this sees the thread idle and wakes it up
+        atomic::fence(Ordering::SeqCst);
+        let termination = &self.shared.termination;
+        if sharing && termination.idle.load(Ordering::Relaxed) > 0 {
+            let _done = termination.done.lock();
+            termination.wake_up.notify_one();
+        }
+    }
+
+    /// The next object to scan, or None once the trace is over.
+    #[inline(always)]
+    fn pop(&self) -> Option<ObjectReference> {
+        self.local.pop().or_else(|| self.pop_slow())
+    }
+
+    #[cold]
+    fn pop_slow(&self) -> Option<ObjectReference> {
+        let termination = &self.shared.termination;
+        loop {
+            if let Some(obj) = self.steal() {
+                return Some(obj);
+            }
+
+            let mut done = termination.done.lock();
+            let idle = termination.idle.fetch_add(1, Ordering::SeqCst) + 1;
+            loop {
+                if *done {
+                    return None;
+                }
+                if self.has_work_to_steal() {
+                    break;
+                }
+                if idle == termination.n_workers {
+                    // nobody has anything left, and nobody can find anything new
+                    *done = true;
+                    termination.wake_up.notify_all();
+                    return None;
+                }
+                termination.wake_up.wait(&mut done);
+            }
+            termination.idle.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    fn steal(&self) -> Option<ObjectReference> {
+        let stealers = &self.shared.stealers;
+        loop {
+            let steal = self.shared.injector.steal_batch_and_pop(&self.local);
+            if let Steal::Success(obj) = steal {
+                return Some(obj);
+            }
+            let mut retry = steal.is_retry();
+
+            // start with the next thread, so the threads do not all rob the same one
+            for i in 1..stealers.len() {
+                let steal =
+                    stealers[(self.id + i) % stealers.len()].steal_batch_and_pop(&self.local);
+                if let Steal::Success(obj) = steal {
+                    return Some(obj);
+                }
+                retry |= steal.is_retry();
+            }
+
+            if !retry {
+                return None;
+            }
+        }
+    }
+
+    /// Checks if other threads are waiting for work, which they can only find in the queues.
+    #[inline(always)]
+    fn has_idle_threads(&self) -> bool {
+        self.shared.termination.idle.load(Ordering::Relaxed) > 0
+    }
+
+    fn has_work_to_steal(&self) -> bool {
+        !self.shared.injector.is_empty() || self.shared.stealers.iter().any(|x| !x.is_empty())
+    }
+}
+
+fn worker_batch_steal_trace(queue: TraceQueue, immix_space: Arc<ImmixSpace>) -> usize {
+    let mut copy_allocator = CopyAllocator::new(immix_space.clone());
+    // minor collections copy every object they reach out of the nursery
+    let minor = immix_space.is_minor_gc();
+    let evacuating = immix_space.defrag.is_in_progress() || minor;
+    // with sticky mark bits, everything marked is old from now on
+    let unlogging = immix_space.sticky.is_enabled();
+    let generational = immix_space.is_generational();
+    // backup traces of reference counting recount every reference from a live object
+    let counting = immix_space.rc.is_enabled();
 
     let trace_map = &immix_space.trace_map;
-    let alloc_map = immix_space.alloc_map.ptr;
-    let line_mark_table = &immix_space.line_mark_table;
+    let alloc_map = immix_space.alloc_map.as_mut_ptr();
+    let object_starts = immix_space.object_starts.as_mut_ptr();
     let (space_start, space_end) = (immix_space.start(), immix_space.end());
 
-    loop {
-        let next = match worker.pop() {
-            Some(v) => v,
-            None => loop {
-                match injector.steal_batch_and_pop(&worker) {
-                    Steal::Empty => {
-                        active_threads.fetch_sub(1, Ordering::SeqCst);
-
-                        while injector.is_empty() {
-                            if active_threads.load(Ordering::SeqCst) == 0 {
-                                return;
-                            }
-                            spin_loop();
-                        }
+    // objects popped from the local queue, waiting for their prefetches to arrive
+    let depth = immix_space.trace_prefetch();
+    let mut prefetched: VecDeque<ObjectReference> = VecDeque::with_capacity(depth);
 
-                        active_threads.fetch_add(1, Ordering::SeqCst);
+    let mut scanned = 0;
+    loop {
+        // the buffer can not be stolen from, so it goes back into the queue while other threads
+        // are idle. Objects are only taken from other threads (or waited for) once it is empty
+        if queue.has_idle_threads() {
+            for obj in prefetched.drain(..) {
+                queue.push(obj);
+            }
+        } else {
+            while prefetched.len() < depth {
+                match queue.local.pop() {
+                    Some(obj) => {
+                        prefetch_object(alloc_map, space_start, obj);
+                        prefetched.push_back(obj);
                     }
-                    Steal::Success(v) => break v,
-                    Steal::Retry => continue,
+                    None => break,
                 }
-            },
+            }
+        }
+        let next = match prefetched.pop_front().or_else(|| queue.pop()) {
+            Some(obj) => obj,
+            None => break,
         };
+        scanned += 1;
 
         let addr = next.to_address();
         assert!(addr >= space_start && addr < space_end);
-        trace_map.mark_as_traced(addr.to_ptr::<()>());
-        line_mark_table.mark_line_live(addr);
+        // minor collections only sweep the nursery, and major ones only the mature space
+        let in_nursery = immix_space.in_nursery(addr);
+        if in_nursery == minor {
+            immix_space.mark_object_lines(next);
+        }
+        // a mature object still referring into the nursery has to stay in the remembered set
+        let remembering = generational && !in_nursery;
+        if counting {
+            for line in immix_space.object_lines(addr) {
+                immix_space.rc.count_line(line);
+            }
+        }
+        if unlogging || remembering || counting {
+            let encode = unsafe { objectmodel::get_ref_byte(alloc_map, space_start, next) };
+            immix_space.remset.unlog(next, encode);
+        }
 
         let mut base = addr;
         loop {
@@ -84,9 +276,32 @@ fn worker_batch_steal_trace(
             );
             macro_rules! steal_process_edge {
                     ($($offset:literal)+) => {{$(
-                        let obj_addr = unsafe { *base.plus($offset).to_ptr::<ObjectReference>() };
-                        if trace_map.is_untraced_and_valid(obj_addr.as_usize() as *const ()) {
-                            injector.push(obj_addr);
+                        let slot = base.plus($offset);
+                        if evacuating {
+                            if let Some(obj) =
+                                evacuate_edge(slot, &immix_space, &mut copy_allocator)
+                            {
+                                queue.push(obj);
+                            }
+                        } else {
+                            let obj_addr = unsafe { *slot.to_ptr::<ObjectReference>() };
+                            // whichever thread claims the object queues it, so it is scanned once
+                            if trace_map.is_untraced_and_valid(obj_addr.as_usize() as *const ())
+                                && unsafe {
+                                    objectmodel::is_object_start(object_starts, space_start, obj_addr)
+                                }
+                                && trace_map.try_mark(obj_addr.as_usize() as *const ())
+                            {
+                                queue.push(obj_addr);
+                            }
+                        }
+                        if remembering
+                            && immix_space.in_nursery(unsafe { *slot.to_ptr::<Address>() })
+                        {
+                            immix_space.remset.remember(next, slot);
+                        }
+                        if counting {
+                            immix_space.rc_count_edge(unsafe { *slot.to_ptr::<Address>() });
                         }
                     )+}};
                 }
@@ -106,4 +321,145 @@ fn worker_batch_steal_trace(
             }
         }
     }
+
+    scanned
+}
+
+/// Fetches the start of `obj`, and its alloc map entry, into the cache ahead of scanning it.
+#[inline(always)]
+fn prefetch_object(alloc_map: *mut u8, space_start: Address, obj: ObjectReference) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+
+        let entry = objectmodel::get_ref_byte_atomic(alloc_map, space_start, obj);
+        _mm_prefetch::<_MM_HINT_T0>(entry.as_ptr() as *const i8);
+        _mm_prefetch::<_MM_HINT_T0>(obj.to_address().to_ptr::<i8>());
+    }
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = (alloc_map, space_start, obj);
+}
+
+/// Copies the young objects referred to by the fields recorded by the field barrier out of the
+/// nursery, before a minor collection. Returns the copies (and the objects which could not be
+/// copied), which still have to be traced. Fields still referring into the nursery afterwards are
+/// remembered again.
+pub fn evacuate_slots(slots: Vec<Address>, immix_space: Arc<ImmixSpace>) -> Vec<ObjectReference> {
+    let mut copy_allocator = CopyAllocator::new(immix_space.clone());
+
+    let mut objects = Vec::new();
+    for slot in slots {
+        if let Some(obj) = evacuate_edge(slot, &immix_space, &mut copy_allocator) {
+            objects.push(obj);
+        }
+        if immix_space.in_nursery(unsafe { *slot.to_ptr::<Address>() }) {
+            immix_space.remset.log(slot);
+        }
+    }
+
+    objects
+}
+
+/// Follows the reference in `slot` during a defragmenting collection, and returns the object to
+/// scan next if there is one. Objects in defrag candidates (or in the nursery, during a minor
+/// collection) are copied out the first time they are reached, and `slot` is updated to point to
+/// the copy. If there is no space left to copy into, the object is marked in place instead.
+#[inline(always)]
+fn evacuate_edge(
+    slot: Address,
+    immix_space: &ImmixSpace,
+    copy_allocator: &mut CopyAllocator,
+) -> Option<ObjectReference> {
+    let obj = unsafe { *slot.to_ptr::<ObjectReference>() };
+    let addr = obj.to_address();
+    if !immix_space.addr_in_space(addr) || !addr.as_usize().is_multiple_of(size_of::<*mut ()>()) {
+        return None;
+    }
+    // minor collections do not trace the mature space
+    let minor = immix_space.is_minor_gc();
+    if minor && !immix_space.in_nursery(addr) {
+        return None;
+    }
+
+    let (alloc_map, space_start) = (immix_space.alloc_map.as_mut_ptr(), immix_space.start());
+    let trace_map = &immix_space.trace_map;
+    let entry = unsafe { objectmodel::get_ref_byte_atomic(alloc_map, space_start, obj) };
+
+    // the start bit is read before the entry: whoever copies the object clears it only once it
+    // has claimed the entry, so if it is gone the entry below is seen as forwarded
+    let is_object = immix_space.object_starts.is_set(addr);
+    atomic::fence(Ordering::Acquire);
+
+    loop {
+        let value = entry.load(Ordering::Acquire);
+        match value {
+            objectmodel::FORWARDED => {
+                // whoever copied the object has already queued the copy
+                unsafe {
+                    *slot.to_ptr_mut::<ObjectReference>() = *addr.to_ptr::<ObjectReference>()
+                };
+                return None;
+            }
+            objectmodel::BEING_FORWARDED => {
+                spin_loop();
+                continue;
+            }
+            _ if !is_object => return None,
+            _ => {}
+        }
+
+        if trace_map.is_traced(addr.to_ptr::<()>()) {
+            return None;
+        }
+        if !(minor || immix_space.defrag.is_candidate(addr)) || immix_space.is_pinned(obj) {
+            return trace_map.try_mark(addr.to_ptr::<()>()).then_some(obj);
+        }
+
+        if entry
+            .compare_exchange(
+                value,
+                objectmodel::BEING_FORWARDED,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            continue;
+        }
+        // a thread which could not copy the object marks it in place and puts the entry back, so
+        // the entry may have been claimed again since the check above. The object is queued then
+        if trace_map.is_traced(addr.to_ptr::<()>()) {
+            entry.store(value, Ordering::Release);
+            return None;
+        }
+
+        let size = immix_space.object_size(obj);
+        return match copy_allocator.alloc(size) {
+            Some(new_addr) => unsafe {
+                ptr::copy_nonoverlapping(addr.to_ptr::<u8>(), new_addr.to_ptr_mut::<u8>(), size);
+                immix_space.alloc_map.store(new_addr, value);
+                immix_space.object_starts.set(new_addr);
+                if immix_space.medium_objects.is_set(addr) {
+                    immix_space.medium_objects.set(new_addr);
+                }
+                trace_map.mark_as_traced(new_addr.to_ptr::<()>());
+
+                let new_obj = new_addr.to_object_reference();
+                *addr.to_ptr_mut::<ObjectReference>() = new_obj;
+                // the old copy is no longer an object for anything but this function. Pairs with
+                // the fence after the start bit is read above
+                atomic::fence(Ordering::Release);
+                immix_space.object_starts.clear(addr);
+                entry.store(objectmodel::FORWARDED, Ordering::Release);
+
+                *slot.to_ptr_mut::<ObjectReference>() = new_obj;
+                Some(new_obj)
+            },
+            None => {
+                trace_map.mark_as_traced(addr.to_ptr::<()>());
+                entry.store(value, Ordering::Release);
+                Some(obj)
+            }
+        };
+    }
 }
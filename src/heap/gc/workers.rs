@@ -0,0 +1,179 @@
+use parking_lot::{Condvar, Mutex};
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+type Job = Arc<dyn Fn(usize) + Send + Sync>;
+
+/// The GC threads of a space. They are spawned at the first collection, and park between
+/// collections until `run` hands them the next job.
+pub struct GcWorkers {
+    n_workers: AtomicUsize,
+    shared: Arc<Shared>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    job_posted: Condvar,
+    job_done: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    job: Option<Job>,
+    // bumped for every job, so workers can tell a new one from the one they just ran
+    job_id: usize,
+    // workers still running the current job
+    running: usize,
+    panic: Option<Box<dyn Any + Send>>,
+    shutdown: bool,
+}
+
+impl GcWorkers {
+    pub fn new() -> GcWorkers {
+        GcWorkers {
+            n_workers: AtomicUsize::new(num_cpus::get()),
+            shared: Arc::new(Shared {
+                state: Mutex::new(State::default()),
+                job_posted: Condvar::new(),
+                job_done: Condvar::new(),
+            }),
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn n_workers(&self) -> usize {
+        self.n_workers.load(Ordering::SeqCst)
+    }
+
+    /// Sets the number of GC threads. Must be called before the first collection.
+    pub fn set_n_workers(&self, n_workers: usize) {
+        assert!(n_workers > 0, "there must be at least one GC thread");
+        assert!(
+            self.threads.lock().is_empty(),
+            "the GC threads have already been started"
+        );
+        self.n_workers.store(n_workers, Ordering::SeqCst);
+    }
+
+    /// Runs `job` on every GC thread, passing each its index, and returns once they all finished.
+    /// A panic in any of them is resumed here.
+    pub fn run<F: Fn(usize) + Send + Sync + 'static>(&self, job: F) {
+        self.start(job);
+        self.wait();
+    }
+
+    /// Starts `job` on every GC thread like `run`, but returns without waiting for it. The job
+    /// keeps the threads busy until `wait` is called, or the next job is started, which both wait
+    /// for it to finish first.
+    pub fn start<F: Fn(usize) + Send + Sync + 'static>(&self, job: F) {
+        let mut threads = self.threads.lock();
+        if threads.is_empty() {
+            self.spawn(&mut threads);
+        }
+
+        let mut state = self.shared.state.lock();
+        while state.running > 0 {
+            self.shared.job_done.wait(&mut state);
+        }
+        // a panic in a job nobody waited for
+        if let Some(payload) = state.panic.take() {
+            drop(state);
+            drop(threads);
+            panic::resume_unwind(payload);
+        }
+
+        state.job = Some(Arc::new(job));
+        state.job_id += 1;
+        state.running = threads.len();
+        self.shared.job_posted.notify_all();
+    }
+
+    /// Waits for the last job started to finish, if it has not yet. A panic in any of the threads
+    /// running it is resumed here.
+    pub fn wait(&self) {
+        let mut state = self.shared.state.lock();
+        while state.running > 0 {
+            self.shared.job_done.wait(&mut state);
+        }
+
+        // the job may hold on to the space, which must not outlive its users
+        state.job = None;
+        if let Some(payload) = state.panic.take() {
+            drop(state);
+            panic::resume_unwind(payload);
+        }
+    }
+
+    fn spawn(&self, threads: &mut Vec<JoinHandle<()>>) {
+        for id in 0..self.n_workers() {
+            let shared = self.shared.clone();
+            threads.push(
+                thread::Builder::new()
+                    .name(format!("gc-worker-{}", id))
+                    .spawn(move || work(shared, id))
+                    .expect("failed to spawn a GC thread"),
+            );
+        }
+    }
+}
+
+impl Default for GcWorkers {
+    fn default() -> GcWorkers {
+        GcWorkers::new()
+    }
+}
+
+impl Drop for GcWorkers {
+    fn drop(&mut self) {
+        self.shared.state.lock().shutdown = true;
+        self.shared.job_posted.notify_all();
+
+        // the last job of a worker may be what drops the workers, and it can not wait for itself
+        for thread in self.threads.lock().drain(..) {
+            if thread.thread().id() != thread::current().id() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+fn work(shared: Arc<Shared>, id: usize) {
+    let mut last_job = 0;
+    loop {
+        let job = {
+            let mut state = shared.state.lock();
+            while state.job_id == last_job && !state.shutdown {
+                shared.job_posted.wait(&mut state);
+            }
+            if state.shutdown {
+                return;
+            }
+
+            last_job = state.job_id;
+            state.job.clone().unwrap()
+        };
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| job(id)));
+        drop(job);
+
+        let mut state = shared.state.lock();
+        if let Err(payload) = result {
+            state.panic.get_or_insert(payload);
+        }
+        state.running -= 1;
+        // a job nobody waits for must not keep what it holds on to alive until the next one, and
+        // it is dropped without the lock, since that may drop the workers themselves
+        let finished = if state.running == 0 {
+            shared.job_done.notify_all();
+            state.job.take()
+        } else {
+            None
+        };
+        drop(state);
+        drop(finished);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::common::{Address, ObjectReference, SideMetadata};
+use crate::heap::immix::card_table::CardTable;
+use crate::heap::immix::soft_dirty::clear_soft_dirty;
+use crate::heap::immix::ImmixSpace;
+use crate::objectmodel;
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// What the write barrier records when a reference is stored into an old object.
+#[repr(usize)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum WriteBarrier {
+    /// Remembers the whole object the first time one of its fields is written to. The next
+    /// collection scans every reference field of it.
+    Object = 0,
+    /// Remembers each field the first time it is written to. Costs more entries when many fields
+    /// of one object are written, but the collection only has to look at those fields.
+    Field = 1,
+    /// Dirties the card holding the object on every store, which is cheaper than checking a bit
+    /// and needs no slow path. The collection scans every object starting in a dirty card.
+    Card = 2,
+    /// Records nothing: stores are found afterwards from the soft-dirty bits the kernel keeps for
+    /// every page, so mutators may store references without going through the barrier at all.
+    /// The collection scans every old object in a page written to (see
+    /// `ImmixSpace::enable_soft_dirty_tracking`).
+    SoftDirty = 3,
+}
+
+/// The entries recorded by the write barrier since the last collection, either objects or fields
+/// (slots) depending on the barrier in use, or dirty cards (see `CardTable`).
+///
+/// Whether a store has to be recorded is decided by a single "unlogged" bit per word: the
+/// collector sets it on old objects (or on all of their reference fields), and the slow path of the
+/// barrier clears it when it records the entry. The bit is cleared for good when the memory is
+/// freed, so objects allocated since the last collection never take the slow path.
+pub struct RememberedSet {
+    barrier: AtomicUsize,
+    unlogged: SideMetadata<1>,
+    entries: Mutex<Vec<Address>>,
+    pub cards: CardTable,
+}
+
+impl RememberedSet {
+    pub fn new(unlogged: SideMetadata<1>, cards: SideMetadata<8>) -> RememberedSet {
+        RememberedSet {
+            barrier: AtomicUsize::new(WriteBarrier::Object as usize),
+            unlogged,
+            entries: Mutex::new(Vec::new()),
+            cards: CardTable::new(cards),
+        }
+    }
+
+    #[inline(always)]
+    pub fn barrier(&self) -> WriteBarrier {
+        match self.barrier.load(Ordering::Relaxed) {
+            0 => WriteBarrier::Object,
+            1 => WriteBarrier::Field,
+            2 => WriteBarrier::Card,
+            _ => WriteBarrier::SoftDirty,
+        }
+    }
+
+    /// The unlogged bits, read directly by the fast path of the barrier.
+    pub fn unlogged_bits(&self) -> *const AtomicU64 {
+        self.unlogged.as_mut_ptr() as *const AtomicU64
+    }
+
+    /// Makes stores into `obj` go through the slow path. `encode` is the alloc map entry of `obj`.
+    #[inline(always)]
+    pub fn unlog(&self, obj: ObjectReference, encode: u8) {
+        match self.barrier() {
+            WriteBarrier::Object => self.unlogged.set(obj.to_address()),
+            WriteBarrier::Field => {
+                for slot in objectmodel::ref_slots(encode, obj) {
+                    self.unlogged.set(slot);
+                }
+            }
+            WriteBarrier::Card | WriteBarrier::SoftDirty => {}
+        }
+    }
+
+    /// Slow path of the barrier. `addr` is the object or the slot, depending on the barrier. Only
+    /// the first thread to clear the bit records the entry.
+    pub fn log(&self, addr: Address) {
+        if self.unlogged.test_and_clear(addr) {
+            self.entries.lock().push(addr);
+        }
+    }
+
+    /// Like `log`, but calls `before_clear` first if the entry is recorded. Mutators which find the
+    /// bit still set wait until `before_clear` is done.
+    pub fn log_with<F: FnOnce()>(&self, addr: Address, before_clear: F) {
+        let mut entries = self.entries.lock();
+        if self.unlogged.is_set(addr) {
+            before_clear();
+            entries.push(addr);
+            self.unlogged.clear(addr);
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_unlogged(&self, addr: Address) -> bool {
+        self.unlogged.is_set(addr)
+    }
+
+    /// Records that `slot` of the old object `obj` refers to a young object, like the slow path of
+    /// the barrier does for a store into it.
+    #[inline(always)]
+    pub fn remember(&self, obj: ObjectReference, slot: Address) {
+        match self.barrier() {
+            WriteBarrier::Object => self.log(obj.to_address()),
+            WriteBarrier::Field => self.log(slot),
+            // an old object still referring to a young one is not necessarily written to again
+            WriteBarrier::Card | WriteBarrier::SoftDirty => self.cards.mark(obj.to_address()),
+        }
+    }
+
+    /// Takes every entry recorded since the last call. The entries become unlogged again, as they
+    /// still belong to old objects. Dirty cards are cleaned, and so are soft-dirty bits.
+    pub fn take(&self) -> Vec<Address> {
+        match self.barrier() {
+            WriteBarrier::Card => self.cards.clear(),
+            WriteBarrier::SoftDirty => {
+                self.cards.clear();
+                clear_soft_dirty().expect("failed to clear soft-dirty bits");
+            }
+            _ => {}
+        }
+
+        let entries = std::mem::take(&mut *self.entries.lock());
+        for &addr in entries.iter() {
+            self.unlogged.set(addr);
+        }
+
+        entries
+    }
+
+    /// Forgets the unlogged bits of every word in `start..end`, once that memory is freed.
+    pub fn clear(&self, start: Address, end: Address) {
+        self.unlogged.clear_range(start, end);
+    }
+}
+
+impl ImmixSpace {
+    /// Chooses what the write barrier remembers. Must be called before any mutator is created.
+    pub fn set_write_barrier(&self, barrier: WriteBarrier) {
+        if barrier == WriteBarrier::SoftDirty {
+            self.check_soft_dirty_tracking();
+        }
+        self.remset
+            .barrier
+            .store(barrier as usize, Ordering::SeqCst);
+    }
+
+    /// Soft-dirty tracking lets stores skip the barrier altogether, while reference counting and
+    /// concurrent marking rely on every store into an old object taking the slow path.
+    pub(super) fn check_soft_dirty_tracking(&self) {
+        assert!(
+            !self.rc.is_enabled() && !self.concurrent.is_enabled(),
+            "soft-dirty tracking can not be combined with reference counting or concurrent marking"
+        );
+    }
+
+    /// Takes the remembered set, and turns it into extra roots for a nursery collection: the
+    /// remembered objects themselves, the young objects referred to by the remembered fields, or
+    /// the old objects in dirty cards (or soft-dirty pages).
+    pub fn remembered_roots(&self) -> Vec<ObjectReference> {
+        match self.remset.barrier() {
+            WriteBarrier::Card => return self.dirty_card_objects(),
+            WriteBarrier::SoftDirty => {
+                self.mark_soft_dirty_cards();
+                return self.dirty_card_objects();
+            }
+            _ => {}
+        }
+        let entries = self.remset.take();
+
+        let objects: Box<dyn Iterator<Item = Address>> = match self.remset.barrier() {
+            WriteBarrier::Object => Box::new(entries.into_iter()),
+            WriteBarrier::Field => Box::new(
+                entries
+                    .into_iter()
+                    .map(|slot| unsafe { *slot.to_ptr::<Address>() }),
+            ),
+            WriteBarrier::Card | WriteBarrier::SoftDirty => unreachable!(),
+        };
+
+        objects
+            .filter(|&x| {
+                self.addr_in_space(x)
+                    && unsafe {
+                        objectmodel::is_object_start(
+                            self.object_starts.as_mut_ptr(),
+                            self.start(),
+                            x.to_object_reference(),
+                        )
+                    }
+            })
+            .map(|x| unsafe { x.to_object_reference() })
+            .collect()
+    }
+}
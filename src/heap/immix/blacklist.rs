@@ -0,0 +1,131 @@
+use crate::common::{Address, SideMetadata};
+use crate::heap::immix::line_mark::LineBitmap;
+use crate::heap::immix::BlockGeometry;
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// Lines hit by ambiguous words (stack slots or registers which fall inside the space, but do not
+/// point to the start of an object) during the most recent conservative root scan. Allocating into
+/// these lines would let the same words retain whatever we place there, so the hole search skips
+/// them until a later collection no longer sees the words.
+///
+/// Instead of clearing the table before each scan, every entry stores the epoch it was recorded
+/// in. Stack scans happen before the collection advances the epoch, so they record the next epoch
+/// and the entries become visible once `advance` is called. Entries start out as 0, which is never
+/// an epoch. Epochs wrap every 255 collections, at which point a stale entry may be picked up
+/// again. This only costs a few lines of allocation space, so we accept it.
+pub struct LineBlacklist {
+    space_start: Address,
+    log_bytes_in_line: usize,
+    // boxed, as the slices handed to blocks point at it and the space moves after creating them
+    epoch: Box<AtomicU8>,
+    lines: SideMetadata<8>,
+    len: usize,
+}
+
+impl LineBlacklist {
+    pub fn new(
+        space_start: Address,
+        space_end: Address,
+        geometry: BlockGeometry,
+        lines: SideMetadata<8>,
+    ) -> LineBlacklist {
+        LineBlacklist {
+            space_start,
+            log_bytes_in_line: geometry.log_bytes_in_line(),
+            epoch: Box::new(AtomicU8::new(1)),
+            lines,
+            len: space_end.diff(space_start) >> geometry.log_bytes_in_line(),
+        }
+    }
+
+    #[inline(always)]
+    fn next_epoch(&self) -> u8 {
+        match self.epoch.load(Ordering::Relaxed).wrapping_add(1) {
+            0 => 1,
+            epoch => epoch,
+        }
+    }
+
+    /// Records the line containing `addr` so it is blacklisted after the next `advance`.
+    #[inline(always)]
+    pub fn record(&self, addr: Address) {
+        self.lines.store(addr, self.next_epoch());
+    }
+
+    /// Makes the lines recorded since the last call the current blacklist. Must only be called
+    /// once all mutators have finished scanning their roots.
+    pub fn advance(&self) {
+        self.epoch.store(self.next_epoch(), Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    pub fn is_blacklisted(&self, index: usize) -> bool {
+        let line = self.space_start.plus(index << self.log_bytes_in_line);
+        self.lines.load(line) == self.epoch.load(Ordering::Relaxed)
+    }
+
+    /// Number of lines blacklisted at the current epoch.
+    pub fn count(&self) -> usize {
+        (0..self.len).filter(|&i| self.is_blacklisted(i)).count()
+    }
+
+    pub fn take_slice(&self, start: usize, len: usize) -> LineBlacklistSlice {
+        debug_assert!(start + len <= self.len);
+
+        LineBlacklistSlice {
+            ptr: unsafe { self.lines.as_mut_ptr().add(start) } as *const AtomicU8,
+            epoch: &*self.epoch,
+            len,
+        }
+    }
+}
+
+impl Debug for LineBlacklist {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:p}", self.lines.as_mut_ptr())
+    }
+}
+
+/// The part of the blacklist covering a single block. Like `LineMarkTableSlice`, this points into
+/// the table owned by the `ImmixSpace`, so it must not outlive the space.
+#[derive(Clone)]
+pub struct LineBlacklistSlice {
+    ptr: *const AtomicU8,
+    epoch: *const AtomicU8,
+    len: usize,
+}
+
+impl LineBlacklistSlice {
+    #[inline(always)]
+    pub fn is_blacklisted(&self, index: usize) -> bool {
+        debug_assert!(index < self.len);
+        unsafe {
+            (*self.ptr.add(index)).load(Ordering::Relaxed) == (*self.epoch).load(Ordering::Relaxed)
+        }
+    }
+
+    /// The epoch the blacklist is at, which changes whenever lines may have been added or dropped.
+    #[inline(always)]
+    pub fn epoch(&self) -> u8 {
+        unsafe { (*self.epoch).load(Ordering::Relaxed) }
+    }
+
+    /// The lines of the block which are blacklisted at the current epoch.
+    pub fn lines(&self) -> LineBitmap {
+        let mut lines = LineBitmap::empty(self.len);
+        for index in 0..self.len {
+            if self.is_blacklisted(index) {
+                lines.insert(index);
+            }
+        }
+        lines
+    }
+}
+
+impl Debug for LineBlacklistSlice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{:p}", self.ptr)
+    }
+}
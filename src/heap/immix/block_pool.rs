@@ -0,0 +1,109 @@
+use crate::heap::immix::geometry::MAX_LOG_LINES_IN_BLOCK;
+use crate::heap::immix::immix_space::ImmixBlock;
+use crate::heap::immix::BlockMark;
+
+use crossbeam::deque::{Injector, Steal};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Which blocks mutators are handed first when they need a new one (see
+/// `ImmixSpace::set_block_policy`).
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub enum BlockPolicy {
+    /// Fill the holes of recyclable blocks before taking free ones, as in the Immix paper. Free
+    /// blocks are kept for medium objects and for evacuation, at the cost of hopping between holes.
+    Recyclable,
+    /// Take free blocks first, and only recycle blocks once they have run out. Allocation bumps
+    /// through whole blocks, but the holes of recyclable blocks stay unused for longer, and are
+    /// more likely to be evacuated instead.
+    Free,
+}
+
+// recyclable blocks are bucketed by the log2 of their number of holes. A block has at most half as
+// many holes as lines, with every other line free
+const HOLE_BUCKETS: usize = MAX_LOG_LINES_IN_BLOCK;
+
+/// The blocks mutators can allocate into, in two pools: free blocks, which do not have a single
+/// line in use, and recyclable blocks, which have free lines between live ones. Recyclable blocks
+/// are ordered by their number of holes, so the ones mutators can fill with the fewest hops are
+/// handed out first.
+pub struct UsableBlocks {
+    free: Injector<Box<ImmixBlock>>,
+    // recyclable blocks, by the log2 of their number of holes
+    recyclable: Vec<Injector<Box<ImmixBlock>>>,
+    prefer_free: AtomicBool,
+}
+
+impl UsableBlocks {
+    pub fn new() -> UsableBlocks {
+        let mut recyclable = Vec::with_capacity(HOLE_BUCKETS);
+        recyclable.resize_with(HOLE_BUCKETS, Injector::new);
+
+        UsableBlocks {
+            free: Injector::new(),
+            recyclable,
+            prefer_free: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_policy(&self, policy: BlockPolicy) {
+        self.prefer_free
+            .store(policy == BlockPolicy::Free, Ordering::SeqCst);
+    }
+
+    /// Adds a block to the pool matching its state, which is set when the block is swept.
+    pub fn push(&self, block: Box<ImmixBlock>) {
+        match block.state() {
+            BlockMark::Free => self.free.push(block),
+            BlockMark::Recyclable => {
+                let bucket = block.holes().max(1).ilog2() as usize;
+                self.recyclable[bucket.min(HOLE_BUCKETS - 1)].push(block);
+            }
+            BlockMark::Full => panic!("full blocks can not be allocated into"),
+        }
+    }
+
+    /// Takes a block for a mutator, from the pool the policy prefers.
+    pub fn take(&self) -> Option<Box<ImmixBlock>> {
+        if self.prefer_free.load(Ordering::Relaxed) {
+            self.take_free().or_else(|| self.take_recyclable())
+        } else {
+            self.take_recyclable().or_else(|| self.take_free())
+        }
+    }
+
+    pub fn take_free(&self) -> Option<Box<ImmixBlock>> {
+        steal_block(&self.free)
+    }
+
+    /// Takes the recyclable block with the fewest holes.
+    pub fn take_recyclable(&self) -> Option<Box<ImmixBlock>> {
+        self.recyclable.iter().find_map(steal_block)
+    }
+
+    pub fn free_len(&self) -> usize {
+        self.free.len()
+    }
+
+    pub fn recyclable_len(&self) -> usize {
+        self.recyclable.iter().map(|x| x.len()).sum()
+    }
+
+    pub fn len(&self) -> usize {
+        self.free_len() + self.recyclable_len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.free.is_empty() && self.recyclable.iter().all(|x| x.is_empty())
+    }
+}
+
+/// Takes a block from `queue`, retrying until it is either found empty or a block is taken.
+pub fn steal_block(queue: &Injector<Box<ImmixBlock>>) -> Option<Box<ImmixBlock>> {
+    loop {
+        match queue.steal() {
+            Steal::Empty => return None,
+            Steal::Success(v) => return Some(v),
+            Steal::Retry => {}
+        }
+    }
+}
@@ -0,0 +1,75 @@
+use crate::common::{Address, ObjectReference, SideMetadata};
+use crate::heap::immix::ImmixSpace;
+use crate::objectmodel;
+
+use std::mem::size_of;
+use std::sync::atomic::AtomicU8;
+
+pub const LOG_BYTES_IN_CARD: usize = 9;
+pub const BYTES_IN_CARD: usize = 1 << LOG_BYTES_IN_CARD;
+
+pub const CARD_CLEAN: u8 = 0;
+pub const CARD_DIRTY: u8 = 1;
+
+/// One byte per 512 bytes of the space, for `WriteBarrier::Card`. The barrier dirties the card
+/// holding the start of every object it stores into, without a slow path or any check. Nursery
+/// collections then scan every object starting in a dirty card, found through the alloc map, and
+/// clean the cards (see `ImmixSpace::dirty_card_objects`).
+pub struct CardTable {
+    cards: SideMetadata<8>,
+}
+
+impl CardTable {
+    pub fn new(cards: SideMetadata<8>) -> CardTable {
+        CardTable { cards }
+    }
+
+    /// The cards, written directly by the fast path of the barrier.
+    pub fn as_ptr(&self) -> *const AtomicU8 {
+        self.cards.as_mut_ptr() as *const AtomicU8
+    }
+
+    #[inline(always)]
+    pub fn mark(&self, addr: Address) {
+        self.cards.store(addr, CARD_DIRTY);
+    }
+
+    /// Cleans every dirty card, and returns where they start.
+    pub fn take_dirty(&self) -> Vec<Address> {
+        self.cards
+            .iter_nonzero()
+            .filter(|&card| self.cards.swap(card, CARD_CLEAN) == CARD_DIRTY)
+            .collect()
+    }
+
+    pub fn clear(&self) {
+        self.cards.clear_all();
+    }
+}
+
+impl ImmixSpace {
+    /// Cleans the dirty cards, and returns the old objects starting in them. In the mature space
+    /// of a generational heap, every object is old. With sticky mark bits, old objects are the
+    /// marked ones.
+    pub fn dirty_card_objects(&self) -> Vec<ObjectReference> {
+        let mut objects = Vec::new();
+        for card in self.remset.cards.take_dirty() {
+            if self.in_nursery(card) {
+                continue;
+            }
+
+            for word in 0..BYTES_IN_CARD / size_of::<*mut ()>() {
+                let obj = unsafe { card.plus(word * size_of::<*mut ()>()).to_object_reference() };
+                if unsafe {
+                    objectmodel::is_object_start(self.object_starts.as_mut_ptr(), self.start(), obj)
+                } && (!self.sticky.is_enabled()
+                    || self.trace_map.is_traced(obj.to_address().to_ptr::<()>()))
+                {
+                    objects.push(obj);
+                }
+            }
+        }
+
+        objects
+    }
+}
@@ -0,0 +1,253 @@
+use crate::common::{Address, ObjectReference};
+use crate::heap::immix::line_mark::LineMark;
+use crate::heap::immix::ImmixSpace;
+use crate::objectmodel;
+
+use log::trace;
+use std::mem::size_of;
+use std::ptr;
+
+/// A live object found by the compactor, and where it will slide to.
+struct LiveObject {
+    from: Address,
+    to: Address,
+    size: usize,
+    encode: u8,
+    medium: bool,
+}
+
+impl ImmixSpace {
+    /// Emergency full-heap mark-compact, used when a sweep leaves the space without free blocks.
+    /// Relies on the marks of the trace that just finished, and slides every marked object towards
+    /// the start of the space in address order (in the style of Lisp 2). Objects in `pinned` stay
+    /// where they are, and are simply skipped over. Must only be called while the world is stopped
+    /// and before the mark state is advanced.
+    pub fn compact(&self, pinned: &[ObjectReference]) {
+        let blocks = self.take_all_blocks();
+        let geometry = self.geometry();
+
+        let mut pinned: Vec<(Address, Address)> = pinned
+            .iter()
+            .map(|x| x.to_address())
+            .filter(|&x| self.is_marked_object(x))
+            .map(|x| {
+                (
+                    x,
+                    x.plus(self.object_size(unsafe { x.to_object_reference() })),
+                )
+            })
+            .collect();
+        pinned.sort_unstable();
+        pinned.dedup();
+
+        // Work out where each object goes. Objects only ever move towards lower addresses, which
+        // lets us move them one by one in address order without overwriting anything still needed.
+        let mut objects = Vec::new();
+        let mut to = self.start();
+        let mut next_pinned = 0;
+
+        for block in blocks.iter() {
+            let block_end = block.start().plus(geometry.bytes_in_block());
+            let mut cursor = block.start();
+
+            while cursor < block_end {
+                let line = cursor.diff(block.start()) >> geometry.log_bytes_in_line();
+                if block.line_mark_table().get(line) == LineMark::Free {
+                    cursor = block
+                        .start()
+                        .plus((line + 1) << geometry.log_bytes_in_line());
+                    continue;
+                }
+
+                if !self.is_marked_object(cursor) {
+                    cursor = cursor.plus(size_of::<*mut ()>());
+                    continue;
+                }
+
+                let obj = unsafe { cursor.to_object_reference() };
+                let size = self.object_size(obj);
+                let is_pinned = pinned.binary_search_by_key(&cursor, |x| x.0).is_ok();
+
+                let mut dest = to;
+                loop {
+                    while next_pinned < pinned.len() && pinned[next_pinned].1 <= dest {
+                        next_pinned += 1;
+                    }
+
+                    if dest.plus(size) > self.block_end(dest) {
+                        // objects never straddle blocks
+                        dest = self.block_end(dest);
+                    } else if next_pinned < pinned.len() && pinned[next_pinned].0 < dest.plus(size)
+                    {
+                        dest = pinned[next_pinned].1;
+                    } else {
+                        break;
+                    }
+                }
+
+                if is_pinned || dest > cursor {
+                    dest = cursor;
+                }
+
+                objects.push(LiveObject {
+                    from: cursor,
+                    to: dest,
+                    size,
+                    encode: unsafe {
+                        objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+                    },
+                    medium: self.medium_objects.is_set(cursor),
+                });
+
+                to = dest.plus(size);
+                cursor = cursor.plus(size);
+            }
+        }
+
+        // Point every reference at the new location of its target, while objects are still at their
+        // old addresses. Objects left in the nursery do not move, but may refer to ones which do.
+        let nursery_objects = self.marked_nursery_objects();
+        let referrers = objects
+            .iter()
+            .map(|x| (x.from, x.encode))
+            .chain(nursery_objects.iter().copied());
+        for (from, encode) in referrers {
+            let obj = unsafe { from.to_object_reference() };
+            for slot in objectmodel::ref_slots(encode, obj) {
+                let value = unsafe { *slot.to_ptr::<Address>() };
+                if let Ok(index) = objects.binary_search_by_key(&value, |x| x.from) {
+                    unsafe { *slot.to_ptr_mut::<Address>() = objects[index].to };
+                }
+            }
+        }
+
+        let mut moved_objects = 0;
+        let mut moved_bytes = 0;
+        for object in objects.iter().filter(|x| x.from != x.to) {
+            unsafe {
+                ptr::copy(
+                    object.from.to_ptr::<u8>(),
+                    object.to.to_ptr_mut::<u8>(),
+                    object.size,
+                )
+            };
+            moved_objects += 1;
+            moved_bytes += object.size;
+        }
+
+        // Rebuild the metadata of the mature space from the new object locations
+        let mature_end = self.nursery_start();
+        self.alloc_map.clear_range(self.start(), mature_end);
+        self.object_starts.clear_range(self.start(), mature_end);
+        self.trace_map.clear(self.start(), mature_end);
+        self.medium_objects.clear_range(self.start(), mature_end);
+        // nothing is marked any more, so every object counts as young until the next collection
+        self.remset.take();
+        self.remset.clear(self.start(), mature_end);
+
+        if self.rc.is_enabled() {
+            self.rc.relocate(objects.iter().map(|x| (x.from, x.to)));
+        }
+
+        let mut blocks = blocks;
+        for block in blocks.iter_mut() {
+            for line in 0..geometry.lines_in_block() {
+                block.line_mark_table_mut().set(line, LineMark::Free);
+            }
+        }
+
+        for object in objects.iter() {
+            self.alloc_map.store(object.to, object.encode);
+            self.object_starts.set(object.to);
+            if object.medium {
+                self.medium_objects.set(object.to);
+            }
+            unsafe {
+                objectmodel::finish_hole(
+                    self.alloc_map.as_mut_ptr(),
+                    self.object_starts.as_mut_ptr(),
+                    self.start(),
+                    object.to.plus(object.size),
+                    self.block_end(object.to),
+                );
+            }
+
+            let block = &mut blocks[object.to.diff(self.start()) >> geometry.log_bytes_in_block()];
+            let first_line = object.to.diff(block.start()) >> geometry.log_bytes_in_line();
+            let last_line = (object.to.plus(object.size).diff(block.start()) - 1)
+                >> geometry.log_bytes_in_line();
+            for line in first_line..=last_line {
+                block.line_mark_table_mut().set(line, LineMark::PrevLive);
+            }
+
+            if self.rc.is_enabled() {
+                for line in self.object_lines(object.to) {
+                    self.rc.count_line(line);
+                }
+            }
+
+            if self.is_generational() || self.rc.is_enabled() {
+                // with a nursery or reference counts, every object here is old
+                let obj = unsafe { object.to.to_object_reference() };
+                self.remset.unlog(obj, object.encode);
+                for slot in objectmodel::ref_slots(object.encode, obj) {
+                    if self.in_nursery(unsafe { *slot.to_ptr::<Address>() }) {
+                        self.remset.remember(obj, slot);
+                    }
+                }
+            }
+        }
+
+        trace!(
+            "compaction moved {} of {} objects ({} bytes)",
+            moved_objects,
+            objects.len(),
+            moved_bytes
+        );
+        if cfg!(debug_assertions) {
+            println!(
+                "compacted     = {} of {} objects moved ({} bytes)",
+                moved_objects,
+                objects.len(),
+                moved_bytes
+            );
+        }
+
+        self.return_all_blocks(blocks);
+    }
+
+    /// The objects left in the nursery which were marked by the last trace, with their alloc map
+    /// entries. Compaction never moves them.
+    fn marked_nursery_objects(&self) -> Vec<(Address, u8)> {
+        let mut objects = Vec::new();
+        let mut cursor = self.nursery_start();
+
+        while cursor < self.end() {
+            if !self.is_marked_object(cursor) {
+                cursor = cursor.plus(size_of::<*mut ()>());
+                continue;
+            }
+
+            let obj = unsafe { cursor.to_object_reference() };
+            objects.push((cursor, unsafe {
+                objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+            }));
+            cursor = cursor.plus(self.object_size(obj));
+        }
+
+        objects
+    }
+
+    #[inline(always)]
+    fn is_marked_object(&self, addr: Address) -> bool {
+        self.addr_in_space(addr)
+            && unsafe {
+                objectmodel::is_object_start(
+                    self.object_starts.as_mut_ptr(),
+                    self.start(),
+                    addr.to_object_reference(),
+                )
+            }
+            && self.trace_map.is_traced(addr.to_ptr::<()>())
+    }
+}
@@ -0,0 +1,326 @@
+use crate::common::Address;
+use crate::heap::immix::immix_space::ImmixBlock;
+use crate::heap::immix::line_mark::LineMark;
+use crate::heap::immix::BlockGeometry;
+use crate::heap::immix::ImmixSpace;
+use crate::objectmodel;
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Fragmentation statistics of a single block, as seen by the last sweep.
+#[derive(Default)]
+struct BlockStats {
+    live_lines: AtomicUsize,
+    holes: AtomicUsize,
+    // contains a root, so nothing may be moved out of it
+    pinned: AtomicBool,
+    candidate: AtomicBool,
+}
+
+/// Bookkeeping for opportunistic evacuation. Each sweep records how many holes every block was
+/// left with, and decides whether the space is fragmented enough to evacuate during the next
+/// collection. That collection then picks the blocks with the most holes as candidates and copies
+/// the objects it finds in them into the blocks of the defrag reserve.
+pub struct DefragState {
+    space_start: Address,
+    log_bytes_in_block: usize,
+    blocks: Vec<BlockStats>,
+    requested: AtomicBool,
+    in_progress: AtomicBool,
+}
+
+impl DefragState {
+    pub fn new(space_start: Address, space_end: Address, geometry: BlockGeometry) -> DefragState {
+        let len = space_end.diff(space_start) >> geometry.log_bytes_in_block();
+
+        let mut blocks = Vec::with_capacity(len);
+        blocks.resize_with(len, BlockStats::default);
+
+        DefragState {
+            space_start,
+            log_bytes_in_block: geometry.log_bytes_in_block(),
+            blocks,
+            requested: AtomicBool::new(false),
+            in_progress: AtomicBool::new(false),
+        }
+    }
+
+    #[inline(always)]
+    fn block_index(&self, addr: Address) -> usize {
+        addr.diff(self.space_start) >> self.log_bytes_in_block
+    }
+
+    pub fn record_block(&self, block_id: usize, live_lines: usize, holes: usize) {
+        let stats = &self.blocks[block_id];
+        stats.live_lines.store(live_lines, Ordering::Relaxed);
+        stats.holes.store(holes, Ordering::Relaxed);
+    }
+
+    pub fn request(&self, requested: bool) {
+        self.requested.store(requested, Ordering::SeqCst);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Prevents the block containing `addr` from being chosen as a candidate in this collection.
+    pub fn pin_block(&self, addr: Address) {
+        self.blocks[self.block_index(addr)]
+            .pinned
+            .store(true, Ordering::Relaxed);
+    }
+
+    /// Picks the blocks with the most holes as candidates, as long as their live lines fit into
+    /// `available_lines`. Returns the ids of the chosen blocks.
+    pub fn select_candidates(&self, available_lines: usize) -> Vec<usize> {
+        let mut blocks: Vec<(usize, usize, usize)> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, x)| !x.pinned.load(Ordering::Relaxed))
+            .map(|(id, x)| {
+                let holes = x.holes.load(Ordering::Relaxed);
+                (id, holes, x.live_lines.load(Ordering::Relaxed))
+            })
+            .filter(|&(_, holes, live_lines)| holes > 1 && live_lines > 0)
+            .collect();
+
+        // most holes first, then the ones which are cheapest to evacuate
+        blocks.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.2.cmp(&b.2)));
+
+        let mut required_lines = 0;
+        let mut candidates = Vec::new();
+        for (id, _, live_lines) in blocks {
+            if required_lines + live_lines > available_lines {
+                break;
+            }
+
+            required_lines += live_lines;
+            self.blocks[id].candidate.store(true, Ordering::Relaxed);
+            candidates.push(id);
+        }
+
+        if !candidates.is_empty() {
+            self.in_progress.store(true, Ordering::SeqCst);
+        }
+
+        candidates
+    }
+
+    #[inline(always)]
+    pub fn is_in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::Relaxed)
+    }
+
+    /// Checks if the object at `addr` should be evacuated in this collection.
+    #[inline(always)]
+    pub fn is_candidate(&self, addr: Address) -> bool {
+        self.is_in_progress()
+            && self.blocks[self.block_index(addr)]
+                .candidate
+                .load(Ordering::Relaxed)
+    }
+
+    /// Clears the candidates and pinned blocks of the current collection.
+    pub fn finish(&self) {
+        for stats in self.blocks.iter() {
+            stats.pinned.store(false, Ordering::Relaxed);
+            stats.candidate.store(false, Ordering::Relaxed);
+        }
+
+        self.in_progress.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Bump allocator used by GC threads to copy objects out of defragmentation candidates. Like
+/// `ImmixMutatorLocal`, it allocates into the holes of one block at a time, but takes its blocks
+/// from the defrag reserve first (see `ImmixSpace::get_copy_block`).
+pub struct CopyAllocator {
+    space: Arc<ImmixSpace>,
+    geometry: BlockGeometry,
+    block: Option<Box<ImmixBlock>>,
+    cursor: Address,
+    limit: Address,
+    line: usize,
+
+    // a block for the medium objects which do not fit in the current hole, and the hole of it
+    // they are copied into (see `alloc_medium`)
+    overflow: Option<Box<ImmixBlock>>,
+    overflow_cursor: Address,
+    overflow_limit: Address,
+    overflow_line: usize,
+}
+
+impl CopyAllocator {
+    pub fn new(space: Arc<ImmixSpace>) -> CopyAllocator {
+        let geometry = space.geometry();
+        CopyAllocator {
+            space,
+            geometry,
+            block: None,
+            cursor: unsafe { Address::null() },
+            limit: unsafe { Address::null() },
+            line: geometry.lines_in_block(),
+            overflow: None,
+            overflow_cursor: unsafe { Address::null() },
+            overflow_limit: unsafe { Address::null() },
+            overflow_line: geometry.lines_in_block(),
+        }
+    }
+
+    /// Allocates `size` bytes, or returns `None` once there are no blocks left to copy into.
+    pub fn alloc(&mut self, size: usize) -> Option<Address> {
+        if size > self.geometry.bytes_in_line() {
+            return self.alloc_medium(size);
+        }
+
+        loop {
+            let end = self.cursor.plus(size);
+            if self.block.is_some() && end <= self.limit {
+                let start = self.cursor;
+                self.cursor = end;
+                return Some(start);
+            }
+
+            if !self.next_hole() {
+                return None;
+            }
+        }
+    }
+
+    /// Medium objects which do not fit in the current hole are copied into an overflow block, as
+    /// mutators allocate them (see `ImmixMutatorLocal::alloc_medium`), rather than skipping the
+    /// rest of the hole to find one they fit in.
+    fn alloc_medium(&mut self, size: usize) -> Option<Address> {
+        let end = self.cursor.plus(size);
+        if self.block.is_some() && end <= self.limit {
+            let start = self.cursor;
+            self.cursor = end;
+            return Some(start);
+        }
+
+        loop {
+            let end = self.overflow_cursor.plus(size);
+            if self.overflow.is_some() && end <= self.overflow_limit {
+                let start = self.overflow_cursor;
+                self.overflow_cursor = end;
+                return Some(start);
+            }
+
+            if !self.next_overflow_hole() {
+                return None;
+            }
+        }
+    }
+
+    fn next_hole(&mut self) -> bool {
+        self.finish_hole();
+
+        loop {
+            if let Some(block) = self.block.as_mut() {
+                if let Some(hole) = block.take_hole(self.line, LineMark::FreshAlloc) {
+                    let log_bytes_in_line = self.geometry.log_bytes_in_line();
+                    self.cursor = block.start().plus(hole.start << log_bytes_in_line);
+                    self.limit = block.start().plus(hole.end << log_bytes_in_line);
+                    self.line = hole.end;
+                    return true;
+                }
+            }
+
+            self.return_block();
+            match self.space.get_copy_block() {
+                Some(block) => {
+                    self.block = Some(block);
+                    self.line = 0;
+                }
+                None => return false,
+            }
+        }
+    }
+
+    fn finish_hole(&mut self) {
+        if let Some(block) = self.block.as_ref() {
+            if self.line > 0 {
+                unsafe {
+                    objectmodel::finish_hole(
+                        self.space.alloc_map.as_mut_ptr(),
+                        self.space.object_starts.as_mut_ptr(),
+                        self.space.start(),
+                        self.cursor,
+                        block.start().plus(self.geometry.bytes_in_block()),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Hands the current block back to the space, where it will be swept with the other blocks
+    /// used since the last collection.
+    pub fn return_block(&mut self) {
+        self.finish_hole();
+        if let Some(block) = self.block.take() {
+            self.space.return_used_block(block);
+        }
+        self.line = self.geometry.lines_in_block();
+    }
+
+    /// Moves on to the next hole of the overflow block, or to a new overflow block once there is
+    /// none left. Returns false if there are no blocks left to copy into.
+    fn next_overflow_hole(&mut self) -> bool {
+        self.finish_overflow_hole();
+
+        loop {
+            if let Some(block) = self.overflow.as_mut() {
+                if let Some(hole) = block.take_hole(self.overflow_line, LineMark::FreshAlloc) {
+                    let log_bytes_in_line = self.geometry.log_bytes_in_line();
+                    self.overflow_cursor = block.start().plus(hole.start << log_bytes_in_line);
+                    self.overflow_limit = block.start().plus(hole.end << log_bytes_in_line);
+                    self.overflow_line = hole.end;
+                    return true;
+                }
+            }
+
+            self.return_overflow_block();
+            match self.space.get_copy_block() {
+                Some(block) => {
+                    self.overflow = Some(block);
+                    self.overflow_line = 0;
+                }
+                None => return false,
+            }
+        }
+    }
+
+    fn finish_overflow_hole(&mut self) {
+        if let Some(block) = self.overflow.as_ref() {
+            if self.overflow_line > 0 {
+                unsafe {
+                    objectmodel::finish_hole(
+                        self.space.alloc_map.as_mut_ptr(),
+                        self.space.object_starts.as_mut_ptr(),
+                        self.space.start(),
+                        self.overflow_cursor,
+                        block.start().plus(self.geometry.bytes_in_block()),
+                    );
+                }
+            }
+        }
+    }
+
+    fn return_overflow_block(&mut self) {
+        self.finish_overflow_hole();
+        if let Some(block) = self.overflow.take() {
+            self.space.return_used_block(block);
+        }
+        self.overflow_line = self.geometry.lines_in_block();
+    }
+}
+
+impl Drop for CopyAllocator {
+    fn drop(&mut self) {
+        self.return_block();
+        self.return_overflow_block();
+    }
+}
@@ -0,0 +1,88 @@
+/// The smallest and largest lines a space can be created with. Lines hold at most 128 words, so
+/// the objects starting in a line can always be counted in a byte (see `RefCounts`).
+const MIN_LOG_BYTES_IN_LINE: usize = 6;
+const MAX_LOG_BYTES_IN_LINE: usize = 10;
+/// The smallest and largest blocks a space can be created with. Blocks are at least a page, and
+/// the space is aligned to the largest block.
+const MIN_LOG_BYTES_IN_BLOCK: usize = 12;
+pub const MAX_LOG_BYTES_IN_BLOCK: usize = 19;
+/// Blocks have at most 512 lines, which bounds the size of the free line bitmaps.
+pub const MAX_LOG_LINES_IN_BLOCK: usize = 9;
+
+const DEFAULT_LOG_BYTES_IN_LINE: usize = 8;
+const DEFAULT_LOG_BYTES_IN_BLOCK: usize = 16;
+
+/// The sizes of the lines and blocks of a space, chosen when it is created (see
+/// `ImmixSpace::with_geometry`). Larger lines make marking and hole searches cheaper, but waste
+/// more of each line around live objects, while larger blocks leave room for larger medium objects
+/// at the cost of coarser defragmentation. The default is the 256 byte lines and 64KB blocks of the
+/// Immix paper. Mutators keep a copy where C code can read it (see `immix_rust.h`).
+#[repr(C)]
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct BlockGeometry {
+    log_bytes_in_line: usize,
+    log_bytes_in_block: usize,
+}
+
+impl BlockGeometry {
+    pub fn new(log_bytes_in_line: usize, log_bytes_in_block: usize) -> BlockGeometry {
+        assert!(
+            (MIN_LOG_BYTES_IN_LINE..=MAX_LOG_BYTES_IN_LINE).contains(&log_bytes_in_line),
+            "lines must be between {} and {} bytes",
+            1 << MIN_LOG_BYTES_IN_LINE,
+            1 << MAX_LOG_BYTES_IN_LINE
+        );
+        assert!(
+            (MIN_LOG_BYTES_IN_BLOCK..=MAX_LOG_BYTES_IN_BLOCK).contains(&log_bytes_in_block),
+            "blocks must be between {} and {} bytes",
+            1 << MIN_LOG_BYTES_IN_BLOCK,
+            1 << MAX_LOG_BYTES_IN_BLOCK
+        );
+        assert!(
+            log_bytes_in_block - log_bytes_in_line <= MAX_LOG_LINES_IN_BLOCK,
+            "blocks can have at most {} lines",
+            1 << MAX_LOG_LINES_IN_BLOCK
+        );
+
+        BlockGeometry {
+            log_bytes_in_line,
+            log_bytes_in_block,
+        }
+    }
+
+    #[inline(always)]
+    pub fn log_bytes_in_line(&self) -> usize {
+        self.log_bytes_in_line
+    }
+
+    #[inline(always)]
+    pub fn bytes_in_line(&self) -> usize {
+        1 << self.log_bytes_in_line
+    }
+
+    #[inline(always)]
+    pub fn log_bytes_in_block(&self) -> usize {
+        self.log_bytes_in_block
+    }
+
+    #[inline(always)]
+    pub fn bytes_in_block(&self) -> usize {
+        1 << self.log_bytes_in_block
+    }
+
+    #[inline(always)]
+    pub fn log_lines_in_block(&self) -> usize {
+        self.log_bytes_in_block - self.log_bytes_in_line
+    }
+
+    #[inline(always)]
+    pub fn lines_in_block(&self) -> usize {
+        1 << self.log_lines_in_block()
+    }
+}
+
+impl Default for BlockGeometry {
+    fn default() -> BlockGeometry {
+        BlockGeometry::new(DEFAULT_LOG_BYTES_IN_LINE, DEFAULT_LOG_BYTES_IN_BLOCK)
+    }
+}
@@ -1,18 +1,21 @@
 use crate::heap::gc;
-use crate::heap::immix;
 use crate::heap::immix::immix_space::ImmixBlock;
-use crate::heap::immix::ImmixSpace;
+use crate::heap::immix::WriteBarrier;
+use crate::heap::immix::SATB_BUFFER_LEN;
+use crate::heap::immix::{BlockGeometry, ImmixSpace};
+use crate::heap::immix::{CARD_DIRTY, LOG_BYTES_IN_CARD};
 use lazy_static::lazy_static;
 use log::trace;
 
-use crate::common::Address;
+use crate::common::{Address, ObjectReference};
 
 use crate::heap::immix::line_mark::LineMark;
+use crate::objectmodel;
 use generational_arena::{Arena, Index};
 use parking_lot::RwLock;
 use std::alloc::Layout;
 use std::mem::size_of;
-use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::*;
 
@@ -30,6 +33,7 @@ pub struct ImmixMutatorLocal {
     // use raw pointer here instead of AddressMapTable
     // to avoid indirection in fast path
     alloc_map: *mut u8,
+    object_starts: *mut u8,
     space_start: Address,
 
     // cursor might be invalid, but Option<Address> is expensive here
@@ -39,11 +43,31 @@ pub struct ImmixMutatorLocal {
     limit: Address,
     line: usize,
 
+    // unlogged bits of the remembered set, and what they are kept for, so the write barrier can
+    // decide on its fast path whether to record a store
+    unlogged_bits: *const AtomicU64,
+    cards: *const AtomicU8,
+    barrier: WriteBarrier,
+    // set while concurrent marking runs, when every store takes the slow path
+    marking: *const AtomicBool,
+    // the line and block sizes of the space, kept here for the fast path
+    geometry: BlockGeometry,
+
     // globally accessible per-thread fields
     pub global: Arc<ImmixMutatorGlobal>,
 
     space: Arc<ImmixSpace>,
     block: Option<Box<ImmixBlock>>,
+
+    // a block for the medium objects which do not fit in the current hole, and the hole of it
+    // they are bumped into (see `alloc_medium`)
+    overflow: Option<Box<ImmixBlock>>,
+    overflow_cursor: Address,
+    overflow_limit: Address,
+    overflow_line: usize,
+
+    // overwritten references not handed to the marking threads yet
+    satb: Vec<ObjectReference>,
 }
 
 #[derive(Default, Debug)]
@@ -63,10 +87,14 @@ impl ImmixMutatorLocal {
             // should not use Address::zero() other than initialization
             self.cursor = Address::null();
             self.limit = Address::null();
+            self.overflow_cursor = Address::null();
+            self.overflow_limit = Address::null();
         }
-        self.line = immix::LINES_IN_BLOCK;
+        self.line = self.geometry.lines_in_block();
+        self.overflow_line = self.geometry.lines_in_block();
 
         self.block = None;
+        self.overflow = None;
     }
 
     pub fn new(space: Arc<ImmixSpace>) -> ImmixMutatorLocal {
@@ -76,16 +104,28 @@ impl ImmixMutatorLocal {
         let id = mutators_lock.insert(global.clone());
         N_MUTATORS.fetch_add(1, Ordering::SeqCst);
 
+        let geometry = space.geometry();
         ImmixMutatorLocal {
             id,
             cursor: unsafe { Address::null() },
             limit: unsafe { Address::null() },
-            line: immix::LINES_IN_BLOCK,
+            line: geometry.lines_in_block(),
             block: None,
-            alloc_map: space.alloc_map.ptr,
+            overflow: None,
+            overflow_cursor: unsafe { Address::null() },
+            overflow_limit: unsafe { Address::null() },
+            overflow_line: geometry.lines_in_block(),
+            alloc_map: space.alloc_map.as_mut_ptr(),
+            object_starts: space.object_starts.as_mut_ptr(),
             space_start: space.start(),
+            unlogged_bits: space.remset.unlogged_bits(),
+            cards: space.remset.cards.as_ptr(),
+            barrier: space.remset.barrier(),
+            marking: space.concurrent.marking_flag(),
             global,
             space,
+            geometry,
+            satb: Vec::new(),
         }
     }
 
@@ -96,6 +136,8 @@ impl ImmixMutatorLocal {
     pub fn destroy(&mut self) {
         {
             self.return_block();
+            self.return_overflow_block();
+            self.flush_satb();
         }
 
         let mut mutators_lock = MUTATORS.write();
@@ -123,8 +165,15 @@ impl ImmixMutatorLocal {
         gc::sync_barrier(self);
     }
 
+    /// Allocates an object. Objects up to a line in size are small, and are bumped into the holes
+    /// of the current block. Larger ones are medium objects, up to a block in size (see
+    /// `alloc_medium`).
     #[inline(always)]
     pub fn alloc(&mut self, layout: Layout) -> Address {
+        if layout.size() > self.geometry.bytes_in_line() {
+            return self.alloc_medium(layout);
+        }
+
         let start = self.cursor.align_up(layout.align());
         let end = start.plus(layout.size());
 
@@ -141,8 +190,61 @@ impl ImmixMutatorLocal {
         unsafe {
             *self
                 .alloc_map
-                .add(addr.diff(self.space_start) / size_of::<*mut ()>()) = encode;
+                .add(addr.diff(self.space_start) / size_of::<*mut ()>()) =
+                objectmodel::alloc_map_entry(encode);
+            objectmodel::set_object_start(self.object_starts, self.space_start, addr);
+        }
+    }
+
+    /// Stores `value` into `slot`, a reference field of `src`. Every store of a reference into an
+    /// object of this space must go through here, so that collections which do not trace the whole
+    /// heap learn about references from old objects to young ones. Only the first store into an
+    /// old object (or field, see `WriteBarrier`) since the last collection takes the slow path.
+    /// While concurrent marking runs, every store does, to record the reference it overwrites.
+    #[inline(always)]
+    pub fn write_ref(&mut self, src: ObjectReference, slot: Address, value: ObjectReference) {
+        let logged_addr = match self.barrier {
+            WriteBarrier::Object => src.to_address(),
+            WriteBarrier::Field => slot,
+            WriteBarrier::SoftDirty => src.to_address(),
+            // nothing is ever unlogged with cards, so only marking can take the slow path
+            WriteBarrier::Card => {
+                let card = src.to_address().diff(self.space_start) >> LOG_BYTES_IN_CARD;
+                unsafe { (*self.cards.add(card)).store(CARD_DIRTY, Ordering::Relaxed) };
+                src.to_address()
+            }
+        };
+
+        let word = logged_addr.diff(self.space_start) / size_of::<*mut ()>();
+        let chunk = unsafe { (*self.unlogged_bits.add(word / 64)).load(Ordering::Relaxed) };
+
+        let marking = unsafe { (*self.marking).load(Ordering::Relaxed) };
+        if chunk & (1 << (word % 64)) != 0 || marking {
+            self.write_ref_slow(src, slot, value);
+        } else {
+            unsafe { *slot.to_ptr_mut::<ObjectReference>() = value };
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    pub fn write_ref_slow(&mut self, src: ObjectReference, slot: Address, value: ObjectReference) {
+        if self.space.concurrent.is_marking() {
+            let old = unsafe { *slot.to_ptr::<ObjectReference>() };
+            if self.space.is_satb_candidate(old) {
+                self.satb.push(old);
+                if self.satb.len() >= SATB_BUFFER_LEN {
+                    self.flush_satb();
+                }
+            }
+        }
+
+        if self.space.rc.is_enabled() {
+            self.space.rc_log(src);
+        } else {
+            self.space.remset.remember(src, slot);
         }
+        unsafe { *slot.to_ptr_mut::<ObjectReference>() = value };
     }
 
     #[inline(never)]
@@ -152,52 +254,20 @@ impl ImmixMutatorLocal {
 
     #[cold]
     pub fn try_alloc_from_local(&mut self, layout: Layout) -> Address {
-        // println!("Trying to allocate from local");
-
-        if self.line < immix::LINES_IN_BLOCK {
-            let opt_next_available_line = {
-                let cur_line = self.line;
-                self.block().get_next_available_line(cur_line)
-            };
-
-            match opt_next_available_line {
-                Some(next_available_line) => {
-                    // println!("next available line is {}", next_available_line);
-
-                    // we can alloc from local blocks
-                    let end_line = self.block().get_next_unavailable_line(next_available_line);
-
-                    // println!("next unavailable line is {}", end_line);
-                    self.cursor = self
-                        .block()
-                        .start()
-                        .plus(next_available_line << immix::LOG_BYTES_IN_LINE);
-                    self.limit = self
-                        .block()
-                        .start()
-                        .plus(end_line << immix::LOG_BYTES_IN_LINE);
-                    self.line = end_line;
-
-                    // unsafe {
-                    //     memsec::memset(self.cursor.to_ptr_mut(), 0, self.limit.diff(self.cursor));
-                    // }
-
-                    for line in next_available_line..end_line {
-                        self.block()
-                            .line_mark_table_mut()
-                            .set(line, LineMark::FreshAlloc);
-                    }
-
-                    self.alloc(layout)
-                }
-                None => {
-                    // println!("no available line in current block");
-                    self.alloc_from_global(layout)
-                }
+        self.finish_hole();
+
+        let (line, mark) = (self.line, self.hole_mark());
+        match self.block.as_mut().and_then(|x| x.take_hole(line, mark)) {
+            Some(hole) => {
+                let log_bytes_in_line = self.geometry.log_bytes_in_line();
+                self.cursor = self.block().start().plus(hole.start << log_bytes_in_line);
+                self.limit = self.block().start().plus(hole.end << log_bytes_in_line);
+                self.line = hole.end;
+
+                self.alloc(layout)
             }
-        } else {
-            // we need to alloc from global space
-            self.alloc_from_global(layout)
+            // no available line left in the current block
+            None => self.alloc_from_global(layout),
         }
     }
 
@@ -205,6 +275,7 @@ impl ImmixMutatorLocal {
         trace!("Mutator{:?}: slowpath: alloc_from_global", self.id);
 
         self.return_block();
+        self.help_marking();
 
         loop {
             // check if yield
@@ -228,19 +299,163 @@ impl ImmixMutatorLocal {
         }
     }
 
+    /// Allocates a medium object. It goes into the current hole if it fits there, like a small
+    /// object. Otherwise it goes into the overflow block, rather than skipping to the next hole big
+    /// enough for it: small objects can still use the rest of the current hole (overflow
+    /// allocation, as in the Immix paper). Tracing marks every line of a medium object, which it
+    /// finds through the medium object bits.
+    #[inline(never)]
+    fn alloc_medium(&mut self, layout: Layout) -> Address {
+        assert!(
+            layout.size() <= self.geometry.bytes_in_block(),
+            "objects larger than a block are not supported"
+        );
+
+        let start = self.cursor.align_up(layout.align());
+        let end = start.plus(layout.size());
+        let addr = if end <= self.limit {
+            self.cursor = end;
+            start
+        } else {
+            self.alloc_overflow(layout)
+        };
+
+        self.space.medium_objects.set(addr);
+        addr
+    }
+
+    fn alloc_overflow(&mut self, layout: Layout) -> Address {
+        loop {
+            let start = self.overflow_cursor.align_up(layout.align());
+            let end = start.plus(layout.size());
+            if end <= self.overflow_limit {
+                self.overflow_cursor = end;
+                return start;
+            }
+
+            self.next_overflow_hole();
+        }
+    }
+
+    /// Moves on to the next hole of the overflow block, or to a new overflow block once there is
+    /// none left. Overflow blocks are free blocks whenever there are any (see
+    /// `ImmixSpace::get_overflow_block`), so a block mostly has a single hole.
+    fn next_overflow_hole(&mut self) {
+        self.finish_overflow_hole();
+
+        let (line, mark) = (self.overflow_line, self.hole_mark());
+        let log_bytes_in_line = self.geometry.log_bytes_in_line();
+        if let Some(block) = self.overflow.as_mut() {
+            if let Some(hole) = block.take_hole(line, mark) {
+                self.overflow_cursor = block.start().plus(hole.start << log_bytes_in_line);
+                self.overflow_limit = block.start().plus(hole.end << log_bytes_in_line);
+                self.overflow_line = hole.end;
+                return;
+            }
+        }
+
+        self.return_overflow_block();
+        self.help_marking();
+
+        loop {
+            self.yieldpoint();
+
+            if let Some(block) = self.space.get_overflow_block() {
+                self.overflow_cursor = block.start();
+                self.overflow_limit = block.start();
+                self.overflow_line = 0;
+                self.overflow = Some(block);
+                return;
+            }
+        }
+    }
+
+    /// How the lines of a new hole are marked: objects allocated while marking are live until the
+    /// next cycle.
+    fn hole_mark(&self) -> LineMark {
+        if self.space.concurrent.is_marking() {
+            LineMark::Live
+        } else {
+            LineMark::FreshAlloc
+        }
+    }
+
+    /// Hands the recorded references over while marking, and with incremental marking, marks a
+    /// slice of the heap for every block taken.
+    fn help_marking(&mut self) {
+        if self.space.concurrent.is_marking() {
+            self.flush_satb();
+            gc::mark_slice(&self.space);
+        }
+    }
+
     pub fn prepare_for_gc(&mut self) {
         self.return_block();
+        self.return_overflow_block();
+        self.flush_satb();
     }
 
     pub fn id(&self) -> Index {
         self.id
     }
 
+    /// Marks the end of the objects allocated in the current hole in the alloc map, so the size of
+    /// the last object can be recovered (see `objectmodel::get_object_size`).
+    fn finish_hole(&mut self) {
+        // no hole has been taken from the current block yet
+        if self.block.is_none() || self.line == 0 {
+            return;
+        }
+
+        let block_end = self.block().start().plus(self.geometry.bytes_in_block());
+        unsafe {
+            objectmodel::finish_hole(
+                self.alloc_map,
+                self.object_starts,
+                self.space_start,
+                self.cursor,
+                block_end,
+            );
+        }
+    }
+
+    fn flush_satb(&mut self) {
+        if !self.satb.is_empty() {
+            self.space.concurrent.push(&mut self.satb);
+        }
+    }
+
     fn return_block(&mut self) {
+        self.finish_hole();
         if self.block.is_some() {
             self.space.return_used_block(self.block.take().unwrap());
         }
     }
+
+    fn finish_overflow_hole(&mut self) {
+        if let Some(block) = self.overflow.as_ref() {
+            if self.overflow_line > 0 {
+                let block_end = block.start().plus(self.geometry.bytes_in_block());
+                unsafe {
+                    objectmodel::finish_hole(
+                        self.alloc_map,
+                        self.object_starts,
+                        self.space_start,
+                        self.overflow_cursor,
+                        block_end,
+                    );
+                }
+            }
+        }
+    }
+
+    fn return_overflow_block(&mut self) {
+        self.finish_overflow_hole();
+        if let Some(block) = self.overflow.take() {
+            self.space.return_used_block(block);
+        }
+        self.overflow_line = self.geometry.lines_in_block();
+    }
     fn block(&mut self) -> &mut ImmixBlock {
         self.block.as_mut().unwrap()
     }
@@ -263,6 +478,29 @@ impl ImmixMutatorLocal {
     }
 }
 
+/// Slow path of the write barrier, for the `write_ref` fast path in `immix_rust.h`.
+#[no_mangle]
+pub extern "C" fn write_ref_slow(
+    mutator: &mut Box<ImmixMutatorLocal>,
+    src: ObjectReference,
+    slot: Address,
+    value: ObjectReference,
+) {
+    mutator.write_ref_slow(src, slot, value);
+}
+
+/// Pins `obj` for C code, see `ImmixSpace::pin`.
+#[no_mangle]
+pub extern "C" fn pin_object(mutator: &mut Box<ImmixMutatorLocal>, obj: ObjectReference) {
+    mutator.space.pin(obj);
+}
+
+/// Releases a pin taken by `pin_object`, see `ImmixSpace::unpin`.
+#[no_mangle]
+pub extern "C" fn unpin_object(mutator: &mut Box<ImmixMutatorLocal>, obj: ObjectReference) {
+    mutator.space.unpin(obj);
+}
+
 impl ImmixMutatorGlobal {
     pub fn new() -> ImmixMutatorGlobal {
         ImmixMutatorGlobal {
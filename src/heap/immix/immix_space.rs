@@ -1,65 +1,269 @@
-use crate::common::AddressMap;
-use crate::common::{Address, TraceMap};
+use crate::common::{Address, ObjectReference, TraceMap};
+use crate::common::{SideMetadata, SideMetadataSpec, SideMetadataTables};
 use crate::heap::gc;
+use crate::heap::gc::GcWorkers;
 use crate::heap::immix;
 
-use crate::heap::immix::line_mark::LineMark;
+use crate::heap::immix::barrier::RememberedSet;
+use crate::heap::immix::blacklist::{LineBlacklist, LineBlacklistSlice};
+use crate::heap::immix::block_pool::{steal_block, BlockPolicy, UsableBlocks};
+use crate::heap::immix::card_table::LOG_BYTES_IN_CARD;
+use crate::heap::immix::defrag::DefragState;
+use crate::heap::immix::geometry::MAX_LOG_BYTES_IN_BLOCK;
+use crate::heap::immix::line_mark::{LineBitmap, LineMark};
 use crate::heap::immix::line_mark::{LineMarkTable, LineMarkTableSlice};
-use crate::heap::immix::BlockMark;
+use crate::heap::immix::rc::RefCounts;
+use crate::heap::immix::satb::ConcurrentMarking;
+use crate::heap::immix::sticky::StickyState;
+use crate::heap::immix::sweep::{SweepState, SweepStats, SweepingDone};
+use crate::heap::immix::{BlockGeometry, BlockMark};
+use crate::objectmodel;
 use crossbeam::deque::{Injector, Steal};
+use log::trace;
 use memmap2::{MmapMut, MmapOptions};
-use std::collections::VecDeque;
+use parking_lot::Mutex;
+use std::mem::size_of;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::*;
 
 #[repr(C)]
 pub struct ImmixSpace {
     start: Address,
     end: Address,
+    // the sizes of lines and blocks
+    geometry: BlockGeometry,
 
-    // TODO: Make SafeAddressMap<u8> atomic (SafeAddressMap<AtomicU8>)
-    // these maps are writable at allocation, read-only at collection
-    pub alloc_map: AddressMap<u8>,
+    // a byte for every word, with the reference fields of each object and the end of each hole
+    // (see `objectmodel`). Written at allocation, read-only at collection
+    pub alloc_map: SideMetadata<8>,
 
-    // these maps are only for collection
+    // a bit for every word, set where an object starts. Written along with `alloc_map`
+    pub object_starts: SideMetadata<1>,
+
+    // a mark bit for every word, only for collection
     pub trace_map: TraceMap,
 
+    // how often each object is pinned through `pin`. Pinned objects are never moved and are
+    // treated as roots
+    pub pin_map: SideMetadata<8>,
+
+    // objects larger than a line, every line of which is marked by tracing (see
+    // `mark_object_lines`), rather than only the first two
+    pub medium_objects: SideMetadata<1>,
+
     // this table will be accessed through unsafe raw pointers. since Rust doesn't provide a data structure for such guarantees:
     // 1. Non-overlapping segments of this table may be accessed concurrently from different mutator threads
     // 2. One element may be written into at the same time by different gc threads during tracing
     pub line_mark_table: LineMarkTable,
 
+    // lines which conservative roots pointed into without hitting an object
+    pub blacklist: LineBlacklist,
+
+    // fragmentation statistics and evacuation candidates
+    pub defrag: DefragState,
+
+    // nursery collection state, used once sticky mark bits are enabled
+    pub sticky: StickyState,
+
+    // old objects or fields with references stored into them (see `ImmixMutatorLocal::write_ref`)
+    pub remset: RememberedSet,
+
+    // reference counts, used once reference counting is enabled
+    pub rc: RefCounts,
+
+    // marking cycle state, used once concurrent marking is enabled
+    pub concurrent: ConcurrentMarking,
+
+    // the threads tracing and sweeping this space
+    pub gc_workers: GcWorkers,
+
+    // sweeping state, used to sweep after mutators resume with concurrent or lazy sweeping
+    pub sweeping: SweepState,
+
+    // objects each GC thread prefetches ahead of the one it is scanning
+    trace_prefetch: AtomicUsize,
+
     total_blocks: usize, // for debug use
 
     mmap: MmapMut,
-    usable_blocks: Injector<Box<ImmixBlock>>,
+    // the tables behind `alloc_map`, `trace_map` and the other side metadata (see `side_metadata`)
+    side_metadata: SideMetadataTables,
+    // free and recyclable blocks, as left by the last sweep
+    usable_blocks: UsableBlocks,
+    // blocks taken by mutators (or GC threads) since the last sweep
     used_blocks: Injector<Box<ImmixBlock>>,
+    // blocks left without a free line by the last sweep. Only a full-heap collection can free
+    // lines in them, so other collections leave them alone
+    full_blocks: Injector<Box<ImmixBlock>>,
+    // blocks waiting for the GC threads to sweep them
+    unswept_blocks: Injector<Box<ImmixBlock>>,
+
+    // With a nursery, mutators allocate into the blocks above `nursery_start` instead, and minor
+    // collections copy the survivors into the blocks below it (the mature space)
+    nursery_start: Address,
+    nursery_blocks: Injector<Box<ImmixBlock>>,
+    nursery_used_blocks: Injector<Box<ImmixBlock>>,
+    total_nursery_blocks: usize,
+    minor_gc: AtomicBool,
+
+    // free blocks held back from mutators, so a defragmenting collection has somewhere to copy to
+    defrag_reserve: Injector<Box<ImmixBlock>>,
+    reserved_blocks: AtomicUsize,
 }
 
-const SPACE_ALIGN: usize = 1 << 19;
+// blocks are aligned to their size, so the space is aligned to the largest one
+const SPACE_ALIGN: usize = 1 << MAX_LOG_BYTES_IN_BLOCK;
+
+const ALLOC_MAP: SideMetadataSpec = SideMetadataSpec::per_word("alloc map", 8);
+const OBJECT_STARTS: SideMetadataSpec = SideMetadataSpec::per_word("object start bits", 1);
+const MARK_BITS: SideMetadataSpec = SideMetadataSpec::per_word("mark bits", 1);
+const PIN_COUNTS: SideMetadataSpec = SideMetadataSpec::per_word("pin counts", 8);
+const MEDIUM_BITS: SideMetadataSpec = SideMetadataSpec::per_word("medium object bits", 1);
+const UNLOGGED_BITS: SideMetadataSpec = SideMetadataSpec::per_word("unlogged bits", 1);
+const REF_COUNTS: SideMetadataSpec = SideMetadataSpec::per_word("reference counts", 8);
+const CARDS: SideMetadataSpec = SideMetadataSpec::new("cards", 8, LOG_BYTES_IN_CARD);
+
+// the tables with a field for every line or block depend on the geometry of the space
+fn line_marks(geometry: BlockGeometry) -> SideMetadataSpec {
+    SideMetadataSpec::new("line marks", 8, geometry.log_bytes_in_line())
+}
+fn line_blacklist(geometry: BlockGeometry) -> SideMetadataSpec {
+    SideMetadataSpec::new("line blacklist", 8, geometry.log_bytes_in_line())
+}
+fn line_counts(geometry: BlockGeometry) -> SideMetadataSpec {
+    SideMetadataSpec::new("line counts", 8, geometry.log_bytes_in_line())
+}
+fn block_epochs(geometry: BlockGeometry) -> SideMetadataSpec {
+    SideMetadataSpec::new("block epochs", 8, geometry.log_bytes_in_block())
+}
+fn mark_epochs(geometry: BlockGeometry) -> SideMetadataSpec {
+    SideMetadataSpec::new("mark epochs", 8, geometry.log_bytes_in_block())
+}
+
+// every side metadata table of the space, laid out in this order
+fn side_metadata(geometry: BlockGeometry) -> [SideMetadataSpec; 13] {
+    [
+        ALLOC_MAP,
+        OBJECT_STARTS,
+        MARK_BITS,
+        PIN_COUNTS,
+        MEDIUM_BITS,
+        UNLOGGED_BITS,
+        REF_COUNTS,
+        CARDS,
+        line_marks(geometry),
+        line_blacklist(geometry),
+        line_counts(geometry),
+        block_epochs(geometry),
+        mark_epochs(geometry),
+    ]
+}
+
+// hold back 1/40 (2.5%) of all blocks as the defrag reserve
+const DEFRAG_RESERVE_RATIO: usize = 40;
+
+// with concurrent marking, start a cycle once fewer than 1/4 of all blocks are left for mutators
+const MARKING_START_RATIO: usize = 4;
+
+// more objects than this would be evicted from the cache again before they are scanned
+const MAX_TRACE_PREFETCH: usize = 64;
 
 impl ImmixSpace {
     pub fn new(space_size: usize) -> ImmixSpace {
+        ImmixSpace::new_generational(space_size, 0)
+    }
+
+    /// Creates a space with a copying nursery of `nursery_size` bytes in front of it (GenImmix).
+    /// Mutators allocate into the nursery, and minor collections evacuate the survivors into the
+    /// `space_size` bytes of the mature space, relying on the write barrier to find references
+    /// from mature objects into the nursery. Objects the collector can not move (those referred to
+    /// by roots, or pinned) stay in the nursery until they are no longer referred to that way.
+    pub fn new_generational(space_size: usize, nursery_size: usize) -> ImmixSpace {
+        ImmixSpace::with_geometry(space_size, nursery_size, BlockGeometry::default())
+    }
+
+    /// Creates a space with lines and blocks of the sizes given by `geometry`, and a nursery of
+    /// `nursery_size` bytes unless it is 0 (see `new_generational`).
+    pub fn with_geometry(
+        space_size: usize,
+        nursery_size: usize,
+        geometry: BlockGeometry,
+    ) -> ImmixSpace {
         // Acquire memory through mmap
         let mut anon_mmap = MmapOptions::new()
-            .len(space_size + SPACE_ALIGN)
+            .len(space_size + nursery_size + SPACE_ALIGN)
             .map_anon()
             .expect("failed to call mmap");
         let start: Address = Address::from_ptr::<u8>(anon_mmap.as_mut_ptr()).align_up(SPACE_ALIGN);
-        let end: Address = start.plus(space_size);
+        let nursery_start: Address = start.plus(space_size).align_up(geometry.bytes_in_block());
+        let end: Address = nursery_start.plus(nursery_size);
 
-        let line_mark_table = LineMarkTable::new(start, end);
+        let side_metadata = SideMetadataTables::new(start, end, &side_metadata(geometry));
+        trace!(
+            "side metadata takes {} bytes",
+            side_metadata.size_in_bytes()
+        );
 
-        let mut ret = ImmixSpace {
-            start,
-            end,
-            mmap: anon_mmap,
+        // every table points into `side_metadata`, which is kept in the space along with them
+        let mut ret = unsafe {
+            ImmixSpace {
+                start,
+                end,
+                geometry,
+                mmap: anon_mmap,
 
-            line_mark_table,
-            trace_map: TraceMap::new(start.as_usize(), end.as_usize()),
-            alloc_map: AddressMap::new(start, end),
-            usable_blocks: Injector::new(),
-            used_blocks: Injector::new(),
-            total_blocks: 0,
+                line_mark_table: LineMarkTable::new(
+                    start,
+                    end,
+                    geometry,
+                    side_metadata.table(&line_marks(geometry)),
+                    side_metadata.table(&block_epochs(geometry)),
+                ),
+                blacklist: LineBlacklist::new(
+                    start,
+                    end,
+                    geometry,
+                    side_metadata.table(&line_blacklist(geometry)),
+                ),
+                trace_map: TraceMap::new(
+                    side_metadata.table(&MARK_BITS),
+                    side_metadata.table(&mark_epochs(geometry)),
+                    geometry.log_bytes_in_block(),
+                ),
+                pin_map: side_metadata.table(&PIN_COUNTS),
+                medium_objects: side_metadata.table(&MEDIUM_BITS),
+                alloc_map: side_metadata.table(&ALLOC_MAP),
+                object_starts: side_metadata.table(&OBJECT_STARTS),
+                defrag: DefragState::new(start, end, geometry),
+                sticky: StickyState::new(),
+                remset: RememberedSet::new(
+                    side_metadata.table(&UNLOGGED_BITS),
+                    side_metadata.table(&CARDS),
+                ),
+                rc: RefCounts::new(
+                    side_metadata.table(&REF_COUNTS),
+                    side_metadata.table(&line_counts(geometry)),
+                ),
+                side_metadata,
+                concurrent: ConcurrentMarking::new(),
+                gc_workers: GcWorkers::new(),
+                sweeping: SweepState::new(),
+                trace_prefetch: AtomicUsize::new(0),
+                usable_blocks: UsableBlocks::new(),
+                used_blocks: Injector::new(),
+                full_blocks: Injector::new(),
+                unswept_blocks: Injector::new(),
+                defrag_reserve: Injector::new(),
+                reserved_blocks: AtomicUsize::new(0),
+                total_blocks: 0,
+                nursery_start,
+                nursery_blocks: Injector::new(),
+                nursery_used_blocks: Injector::new(),
+                total_nursery_blocks: 0,
+                minor_gc: AtomicBool::new(false),
+            }
         };
 
         ret.init_blocks();
@@ -71,21 +275,114 @@ impl ImmixSpace {
         let mut id = 0;
         let mut block_start = self.start;
         let mut line = 0;
+        let bytes_in_block = self.geometry.bytes_in_block();
+        let lines_in_block = self.geometry.lines_in_block();
 
-        while block_start.plus(immix::BYTES_IN_BLOCK) <= self.end {
-            self.usable_blocks.push(Box::new(ImmixBlock {
+        let mut blocks = Vec::new();
+        while block_start.plus(bytes_in_block) <= self.end {
+            blocks.push(Box::new(ImmixBlock {
                 id,
-                state: immix::BlockMark::Usable,
+                state: immix::BlockMark::Free,
+                holes: 1,
                 start: block_start,
-                line_mark_table: self.line_mark_table.take_slice(line, immix::LINES_IN_BLOCK),
+                free_lines: LineBitmap::full(lines_in_block),
+                blacklisted: LineBitmap::empty(lines_in_block),
+                blacklist_epoch: 0,
+                line_mark_table: self.line_mark_table.take_slice(line, lines_in_block),
+                blacklist: self.blacklist.take_slice(line, lines_in_block),
             }));
 
             id += 1;
-            block_start = block_start.plus(immix::BYTES_IN_BLOCK);
-            line += immix::LINES_IN_BLOCK;
+            block_start = block_start.plus(bytes_in_block);
+            line += lines_in_block;
+        }
+
+        let (mature, nursery): (Vec<_>, Vec<_>) = blocks
+            .into_iter()
+            .partition(|x| x.start() < self.nursery_start);
+        self.total_blocks = mature.len();
+        self.total_nursery_blocks = nursery.len();
+
+        for block in nursery {
+            self.nursery_blocks.push(block);
+        }
+
+        for block in mature {
+            if self.reserved_blocks.load(Ordering::Relaxed) < self.defrag_reserve_target() {
+                self.reserve_block(block);
+            } else {
+                self.usable_blocks.push(block);
+            }
         }
+    }
+
+    fn defrag_reserve_target(&self) -> usize {
+        self.total_blocks / DEFRAG_RESERVE_RATIO
+    }
 
-        self.total_blocks = id;
+    fn reserve_block(&self, block: Box<ImmixBlock>) {
+        self.reserved_blocks.fetch_add(1, Ordering::SeqCst);
+        self.defrag_reserve.push(block);
+    }
+
+    /// Takes a block for GC threads to copy objects into while evacuating. Blocks come from the
+    /// defrag reserve first, then from the free blocks, and last from the recyclable blocks which
+    /// are not being evacuated themselves.
+    pub fn get_copy_block(&self) -> Option<Box<ImmixBlock>> {
+        if let Some(block) = steal_block(&self.defrag_reserve) {
+            self.reserved_blocks.fetch_sub(1, Ordering::SeqCst);
+            return Some(block);
+        }
+
+        if let Some(block) = self.usable_blocks.take_free() {
+            return Some(block);
+        }
+
+        loop {
+            let block = self.usable_blocks.take_recyclable()?;
+            if self.defrag.is_candidate(block.start()) {
+                self.used_blocks.push(block);
+                continue;
+            }
+
+            return Some(block);
+        }
+    }
+
+    /// Chooses the blocks to evacuate in this collection, if the last sweep asked for it. Blocks
+    /// which contain a root are never chosen, since roots can not be updated to point to a copy.
+    pub fn prepare_defrag<'a, I: Iterator<Item = &'a ObjectReference>>(&self, roots: I) {
+        if !self.defrag.is_requested() {
+            return;
+        }
+
+        for root in roots {
+            if self.addr_in_space(root.to_address()) {
+                self.defrag.pin_block(root.to_address());
+            }
+        }
+
+        let available_lines =
+            self.reserved_blocks.load(Ordering::SeqCst) * self.geometry.lines_in_block();
+        let candidates = self.defrag.select_candidates(available_lines);
+
+        // Candidates waiting in the usable queue have to be swept at the end of this collection
+        // to reclaim the lines they were evacuated from
+        let mut usable = Vec::new();
+        while let Some(block) = self.usable_blocks.take() {
+            usable.push(block);
+        }
+        for block in usable {
+            if self.defrag.is_candidate(block.start()) {
+                self.used_blocks.push(block);
+            } else {
+                self.usable_blocks.push(block);
+            }
+        }
+
+        if cfg!(debug_assertions) {
+            println!("defrag candidates = {} blocks", candidates.len());
+        }
     }
 
     pub fn return_used_block(&self, old: Box<ImmixBlock>) {
@@ -93,92 +390,536 @@ impl ImmixSpace {
         // This avoids explicit ownership transferring
         // If we explicitly transfer ownership, the function needs to own the Mutator in order to move the ImmixBlock out of it (see ImmixMutatorLocal.alloc_from_global()),
         // and this will result in passing the Mutator object as value (instead of a borrowed reference) all the way in the allocation
-        self.used_blocks.push(old);
+        if self.in_nursery(old.start()) {
+            self.nursery_used_blocks.push(old);
+        } else {
+            self.used_blocks.push(old);
+        }
     }
 
+    /// Takes a block for a mutator to allocate into, as the block policy prefers (see
+    /// `set_block_policy`). Returns None once there are none left, after triggering a collection.
     pub fn get_next_usable_block(&self) -> Option<Box<ImmixBlock>> {
         loop {
-            match self.usable_blocks.steal() {
-                Steal::Empty => {
+            let block = if self.is_generational() {
+                steal_block(&self.nursery_blocks)
+            } else {
+                self.usable_blocks.take()
+            };
+
+            match block {
+                None => {
+                    // blocks left unswept by the last pause are swept as mutators need them. Those
+                    // of a generational space only take nursery blocks, so they leave the mature
+                    // space to the pause, and lazy sweeping is off for them
+                    if !self.is_generational() && self.sweep_unswept_block() {
+                        continue;
+                    }
+
+                    // the GC threads may still be sweeping their last blocks
+                    if self.sweeping.is_sweeping() {
+                        self.sweeping.wait();
+                        continue;
+                    }
+
+                    // a block may have been put back since
+                    let generational = self.is_generational();
+                    if (generational && !self.nursery_blocks.is_empty())
+                        || (!generational && !self.usable_blocks.is_empty())
+                    {
+                        continue;
+                    }
+
                     gc::trigger_gc();
                     return None;
                 }
-                Steal::Success(v) => return Some(v),
-                Steal::Retry => {}
+                Some(mut v) => {
+                    // Every free line of this block is blacklisted, so leave it for the next sweep
+                    if v.get_next_available_line(0).is_none() {
+                        self.return_used_block(v);
+                        continue;
+                    }
+
+                    if self.should_start_marking() {
+                        gc::trigger_gc();
+                    }
+
+                    return Some(v);
+                }
             }
         }
     }
 
-    pub fn sweep(&self) {
-        let mut free_lines = 0;
-        let mut usable_blocks = 0;
-        let mut full_blocks = 0;
+    /// Takes a block for the medium objects which do not fit in the hole a mutator is allocating
+    /// into (overflow allocation). Free blocks are taken first whatever the block policy, since a
+    /// medium object could rarely be fitted into a recyclable block without skipping holes.
+    pub fn get_overflow_block(&self) -> Option<Box<ImmixBlock>> {
+        if !self.is_generational() {
+            if let Some(block) = self.usable_blocks.take_free() {
+                if self.should_start_marking() {
+                    gc::trigger_gc();
+                }
+
+                return Some(block);
+            }
+        }
+
+        self.get_next_usable_block()
+    }
 
-        // let mut used_blocks_lock = self.used_blocks.lock();
-        // let mut usable_blocks_lock = self.usable_blocks.lock();
+    /// Sweeps the blocks used since the last collection, on the GC threads. Returns true if the
+    /// space is exhausted, meaning it is left without a single free block (only scattered free
+    /// lines, if any). After a nursery collection, lines which were live before it are kept, since
+    /// the old objects in them were not traced.
+    ///
+    /// Only blocks which may have changed since the last sweep are swept: those allocated into
+    /// since, and after a full-heap collection, the recyclable and full blocks too. The others are
+    /// counted as the last sweep left them.
+    ///
+    /// With concurrent sweeping, this returns as soon as a free block is available to mutators, and
+    /// the rest of the blocks are swept after they resume. Lazy sweeping returns without sweeping
+    /// any, and never reports the space exhausted (see `SweepState`).
+    pub fn sweep(self: &Arc<Self>, full_heap: bool) -> bool {
+        // collections are normally triggered because mutators ran out of blocks
+        let out_of_blocks = self.usable_blocks.is_empty();
+
+        // objects in the recyclable blocks mutators did not get to may have died since the last
+        // sweep, but only a full-heap collection finds out. Free blocks have nothing to sweep
+        let mut stats = SweepStats::default();
+        let mut untouched = Vec::new();
+        while let Some(block) = self.usable_blocks.take() {
+            if full_heap && block.state() == BlockMark::Recyclable {
+                self.unswept_blocks.push(block);
+            } else {
+                untouched.push(block);
+            }
+        }
+        stats.skipped_blocks = untouched.len();
+        for block in untouched {
+            let (free_lines, holes) = (block.free_lines.count(), block.holes());
+            self.release_block(block, free_lines, holes, &mut stats);
+        }
+
+        while let Some(block) = steal_block(&self.used_blocks) {
+            self.unswept_blocks.push(block);
+        }
+        if full_heap {
+            while let Some(block) = steal_block(&self.full_blocks) {
+                self.unswept_blocks.push(block);
+            }
+        } else {
+            stats.skipped_blocks += self.full_blocks.len();
+        }
+
+        // mutators of a generational space never sweep
+        let lazy = self.sweeping.is_lazy() && !self.is_generational();
+        if lazy && !self.sweeping.take_exhausted() && !self.unswept_blocks.is_empty() {
+            self.sweeping.defer(full_heap, stats);
+            return false;
+        }
+
+        stats.add(&self.sweep_blocks(full_heap, lazy || self.sweeping.is_concurrent()));
+        let exhausted = (out_of_blocks && stats.free_blocks + stats.reserved_blocks == 0)
+            || self.usable_blocks.is_empty();
+
+        if self.unswept_blocks.is_empty() {
+            self.finish_sweep(&stats);
+            return exhausted;
+        }
+
+        // the counts are completed by whoever sweeps the rest, and reported before the next
+        // collection (see `finish_sweeping`)
+        self.sweeping.defer(full_heap, stats);
+        if self.sweeping.is_concurrent() {
+            // the GC threads keep sweeping, and the last to run out of blocks reports it
+            let space = self.clone();
+            let running = AtomicUsize::new(self.gc_workers.n_workers());
+            self.sweeping.start();
+            self.gc_workers.start(move |_| {
+                let _done = SweepingDone::new(&space.sweeping, &running);
+                let stats = space.sweep_some_blocks(full_heap, None);
+                space.sweeping.add(&stats);
+            });
+        }
+
+        exhausted
+    }
 
-        let mut live_blocks: VecDeque<Box<ImmixBlock>> =
-            VecDeque::with_capacity(self.used_blocks.len());
+    /// Sweeps the blocks in the unswept queue on every GC thread, and returns what they found. With
+    /// `until_free_block`, the threads stop as soon as mutators have a free block to allocate into.
+    pub(super) fn sweep_blocks(
+        self: &Arc<Self>,
+        full_heap: bool,
+        until_free_block: bool,
+    ) -> SweepStats {
+        // free blocks the last sweep left untouched may already be waiting for mutators
+        let found_free_block = Arc::new(AtomicBool::new(self.usable_blocks.free_len() > 0));
+        let total = Arc::new(Mutex::new(SweepStats::default()));
+
+        let space = self.clone();
+        let total_handle = total.clone();
+        self.gc_workers.run(move |_| {
+            let found_free_block = until_free_block.then_some(&*found_free_block);
+            let stats = space.sweep_some_blocks(full_heap, found_free_block);
+            total_handle.lock().add(&stats);
+        });
+
+        let stats = std::mem::take(&mut *total.lock());
+        stats
+    }
+
+    /// Sweeps blocks from the unswept queue on one GC thread until it is empty, and returns what
+    /// the thread found. With `found_free_block`, the thread stops as soon as it is set and
+    /// mutators have a free block to allocate into, and sets it when it frees a block.
+    fn sweep_some_blocks(
+        &self,
+        full_heap: bool,
+        found_free_block: Option<&AtomicBool>,
+    ) -> SweepStats {
+        let mut stats = SweepStats::default();
+        // full blocks are only put back once the queue is empty, so they are not swept again
+        let mut full_blocks = Vec::new();
 
         loop {
-            // let mut block = used_blocks_lock.pop_front().unwrap();
-            let mut block = match self.used_blocks.steal() {
+            if found_free_block.is_some_and(|x| x.load(Ordering::Relaxed))
+                && !self.usable_blocks.is_empty()
+            {
+                break;
+            }
+
+            let mut block = match self.unswept_blocks.steal() {
                 Steal::Empty => break,
                 Steal::Success(v) => v,
                 Steal::Retry => continue,
             };
 
-            let mut has_free_lines = false;
+            let (free_lines, holes) = self.sweep_block(&mut block, full_heap);
+            if free_lines == self.geometry.lines_in_block() {
+                if let Some(found_free_block) = found_free_block {
+                    found_free_block.store(true, Ordering::Relaxed);
+                }
+            }
+
+            if let Some(block) = self.release_block(block, free_lines, holes, &mut stats) {
+                full_blocks.push(block);
+            }
+        }
 
-            {
-                let cur_line_mark_table = block.line_mark_table_mut();
-                for i in 0..cur_line_mark_table.len() {
-                    if cur_line_mark_table.get(i) != LineMark::Live
-                        && cur_line_mark_table.get(i) != LineMark::ConservLive
-                    {
-                        has_free_lines = true;
-                        cur_line_mark_table.set(i, LineMark::Free);
+        for block in full_blocks {
+            self.full_blocks.push(block);
+        }
+        stats
+    }
 
-                        free_lines += 1;
-                    }
+    /// Sweeps one of the blocks left unswept by the last pause, and puts it where it belongs.
+    /// Returns false if there are none left.
+    fn sweep_unswept_block(&self) -> bool {
+        let Some(mut block) = steal_block(&self.unswept_blocks) else {
+            return false;
+        };
+
+        let mut stats = SweepStats::default();
+        let (free_lines, holes) = self.sweep_block(&mut block, self.sweeping.is_full_heap());
+        if let Some(block) = self.release_block(block, free_lines, holes, &mut stats) {
+            self.full_blocks.push(block);
+        }
+        self.sweeping.add(&stats);
+
+        true
+    }
+
+    /// Frees the lines of `block` which were not marked by the last trace, and returns the number
+    /// of free lines and holes it is left with.
+    fn sweep_block(&self, block: &mut ImmixBlock, full_heap: bool) -> (usize, usize) {
+        let block_start = block.start();
+        let mut free_lines = 0;
+        let mut holes = 0;
+        let mut free = LineBitmap::empty(block.line_mark_table().len());
+
+        // the lines freed since the last live or already free line, whose metadata still has to be
+        // cleared. Runs are cleared at once, which is cheaper for the bit-packed tables
+        let mut dead_lines = 0..0;
+
+        let line_mark_table = block.line_mark_table_mut();
+        let mut in_hole = false;
+        for i in 0..line_mark_table.len() {
+            let mark = line_mark_table.get(i);
+            let live = mark == LineMark::Live || (!full_heap && mark == LineMark::PrevLive);
+
+            if live || mark == LineMark::Free {
+                self.clear_dead_lines(block_start, &dead_lines);
+                dead_lines = i + 1..i + 1;
+            } else {
+                dead_lines.end = i + 1;
+            }
+
+            if live {
+                // old lines kept by a nursery collection are carried over into the next epoch
+                if mark == LineMark::PrevLive {
+                    line_mark_table.set(i, LineMark::Live);
                 }
+                in_hole = false;
+                continue;
+            }
+            // lines marked in older epochs are free once the epoch advances, fresh ones are not
+            if mark == LineMark::FreshAlloc {
+                line_mark_table.set(i, LineMark::Free);
+            }
 
-                // release the mutable borrow of 'block'
+            if !in_hole {
+                holes += 1;
+                in_hole = true;
             }
+            free_lines += 1;
+            free.insert(i);
+        }
+        self.clear_dead_lines(block_start, &dead_lines);
+        line_mark_table.advance();
+        block.set_free_lines(free);
 
-            if has_free_lines {
-                block.set_state(BlockMark::Usable);
-                usable_blocks += 1;
+        (free_lines, holes)
+    }
 
-                // usable_blocks_lock.push_front(block);
-                self.usable_blocks.push(block);
+    /// Forgets the dead objects in the given lines of the block at `block_start`, so they can not
+    /// be found by later scans.
+    fn clear_dead_lines(&self, block_start: Address, lines: &Range<usize>) {
+        if lines.is_empty() {
+            return;
+        }
+
+        let start = block_start.plus(lines.start << self.geometry.log_bytes_in_line());
+        let end = block_start.plus(lines.end << self.geometry.log_bytes_in_line());
+        self.alloc_map.clear_range(start, end);
+        self.object_starts.clear_range(start, end);
+        self.trace_map.clear(start, end);
+        self.medium_objects.clear_range(start, end);
+        self.remset.clear(start, end);
+        if self.rc.is_enabled() {
+            self.rc.clear(start, end);
+        }
+    }
+
+    /// Empties the nursery after a minor collection. Everything left in it either was copied out
+    /// or is dead, except for the objects the collector could not move. The lines holding those are
+    /// kept until a later minor collection no longer finds them live.
+    pub fn sweep_nursery(&self) {
+        let mut kept_blocks = Vec::new();
+        let mut kept_lines = 0;
+
+        // like in the mature space, blocks mutators did not get to are swept too
+        loop {
+            match self.nursery_blocks.steal() {
+                Steal::Empty => break,
+                Steal::Success(v) => self.nursery_used_blocks.push(v),
+                Steal::Retry => {}
+            }
+        }
+
+        loop {
+            let mut block = match self.nursery_used_blocks.steal() {
+                Steal::Empty => break,
+                Steal::Success(v) => v,
+                Steal::Retry => continue,
+            };
+
+            let (free_lines, _) = self.sweep_block(&mut block, true);
+            kept_lines += self.geometry.lines_in_block() - free_lines;
+            if free_lines > 0 {
+                self.nursery_blocks.push(block);
             } else {
-                block.set_state(BlockMark::Full);
-                full_blocks += 1;
-                live_blocks.push_front(block);
+                kept_blocks.push(block);
             }
         }
 
-        // used_blocks_lock.append(&mut live_blocks);
-        for block in live_blocks {
-            self.used_blocks.push(block);
+        for block in kept_blocks {
+            self.nursery_used_blocks.push(block);
         }
 
+        // the objects which stayed are young again for the next minor collection
+        self.trace_map.clear(self.nursery_start, self.end);
+
+        if cfg!(debug_assertions) {
+            println!(
+                "nursery       = {} of {} lines kept",
+                kept_lines,
+                self.total_nursery_blocks * self.geometry.lines_in_block()
+            );
+        }
+
+        if self.nursery_blocks.is_empty() {
+            panic!("Out of memory in nursery");
+        }
+    }
+
+    /// Checks if the mature space might not be able to take every survivor of the next minor
+    /// collection, in which case it should be collected now.
+    pub fn is_mature_space_low(&self) -> bool {
+        self.usable_blocks.len() + self.reserved_blocks.load(Ordering::SeqCst)
+            < self.total_nursery_blocks
+    }
+
+    /// Checks if mutators are running low on blocks, and a concurrent marking cycle should start
+    /// so it can finish before they run out.
+    pub fn should_start_marking(&self) -> bool {
+        self.concurrent.is_enabled()
+            && !self.concurrent.is_marking()
+            && !self.sweeping.is_sweeping()
+            && self.usable_blocks.len() + self.unswept_blocks.len()
+                < self.total_blocks / MARKING_START_RATIO
+    }
+
+    /// Checks if mutators have no block left to allocate into.
+    pub fn is_out_of_blocks(&self) -> bool {
+        self.usable_blocks.is_empty()
+    }
+
+    /// Clears the marks left in the nursery by a full-heap collection.
+    pub fn clear_nursery_marks(&self) {
+        self.trace_map.clear(self.nursery_start, self.end);
+    }
+
+    /// Puts a swept block in the defrag reserve, the free blocks or the recyclable blocks,
+    /// depending on how many free lines it has. Full blocks are handed back to the caller.
+    fn release_block(
+        &self,
+        mut block: Box<ImmixBlock>,
+        free_lines: usize,
+        holes: usize,
+        stats: &mut SweepStats,
+    ) -> Option<Box<ImmixBlock>> {
+        let lines_in_block = self.geometry.lines_in_block();
+        stats.free_lines += free_lines;
+        self.defrag
+            .record_block(block.id(), lines_in_block - free_lines, holes);
+
+        block.set_holes(holes);
+        if free_lines == lines_in_block {
+            block.set_state(BlockMark::Free);
+
+            // GC threads release blocks at the same time, so count the block in before reserving it
+            let target = self.defrag_reserve_target();
+            let reserving = self
+                .reserved_blocks
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |x| {
+                    (x < target).then_some(x + 1)
+                })
+                .is_ok();
+            if reserving {
+                stats.reserved_blocks += 1;
+                self.defrag_reserve.push(block);
+            } else {
+                stats.free_blocks += 1;
+                self.usable_blocks.push(block);
+            }
+            None
+        } else if free_lines > 0 {
+            block.set_state(BlockMark::Recyclable);
+            stats.recyclable_blocks += 1;
+            stats.recyclable_lines += free_lines;
+            self.usable_blocks.push(block);
+            None
+        } else {
+            block.set_state(BlockMark::Full);
+            stats.full_blocks += 1;
+            Some(block)
+        }
+    }
+
+    pub(super) fn finish_sweep(&self, stats: &SweepStats) {
+        // Many partially used blocks with little free space in each means mutators keep hopping
+        // between small holes. Evacuate the worst of them during the next collection.
+        self.defrag.finish();
+        self.defrag.request(
+            stats.recyclable_blocks * 4 > self.total_blocks
+                && stats.recyclable_lines * 2
+                    < stats.recyclable_blocks * self.geometry.lines_in_block(),
+        );
+
         if cfg!(debug_assertions) {
             println!(
                 "free lines    = {} of {} total",
-                free_lines,
-                self.total_blocks * immix::LINES_IN_BLOCK
+                stats.free_lines,
+                self.total_blocks * self.geometry.lines_in_block()
+            );
+            println!("free blocks   = {}", stats.free_blocks);
+            println!(
+                "recyclable    = {} blocks ({} free lines)",
+                stats.recyclable_blocks, stats.recyclable_lines
             );
-            println!("usable blocks = {}", usable_blocks);
-            println!("full blocks   = {}", full_blocks);
+            println!("reserved      = {} blocks", stats.reserved_blocks);
+            println!("full blocks   = {}", stats.full_blocks);
+            println!("skipped       = {} blocks", stats.skipped_blocks);
+            println!("blacklisted   = {} lines", self.blacklist.count());
+            println!("defrag next   = {}", self.defrag.is_requested());
         }
 
-        if full_blocks == self.total_blocks {
-            panic!("Out of memory in Immix Space");
+        debug_assert!(
+            stats.free_blocks + stats.recyclable_blocks + stats.reserved_blocks + stats.full_blocks
+                <= self.total_blocks
+        );
+    }
+
+    /// Takes every block out of the block lists, in address order. Only used while the world is
+    /// stopped.
+    #[allow(clippy::vec_box)] // blocks move between the queues boxed
+    pub(super) fn take_all_blocks(&self) -> Vec<Box<ImmixBlock>> {
+        debug_assert!(self.unswept_blocks.is_empty());
+
+        let mut blocks = Vec::with_capacity(self.total_blocks);
+        while let Some(block) = self.usable_blocks.take() {
+            blocks.push(block);
+        }
+        for queue in [&self.used_blocks, &self.full_blocks, &self.defrag_reserve] {
+            while let Some(block) = steal_block(queue) {
+                blocks.push(block);
+            }
+        }
+        self.reserved_blocks.store(0, Ordering::SeqCst);
+
+        blocks.sort_unstable_by_key(|x| x.id());
+        blocks
+    }
+
+    /// Puts blocks taken by `take_all_blocks` back into the block lists, according to their line
+    /// marks. Any line which is not free is treated as live.
+    #[allow(clippy::vec_box)]
+    pub(super) fn return_all_blocks(&self, blocks: Vec<Box<ImmixBlock>>) {
+        let mut stats = SweepStats::default();
+
+        for mut block in blocks {
+            let table = block.line_mark_table();
+            let mut free = LineBitmap::empty(table.len());
+            for i in 0..table.len() {
+                if table.get(i) == LineMark::Free {
+                    free.insert(i);
+                }
+            }
+            let (free_lines, holes) = (free.count(), free.holes());
+            block.set_free_lines(free);
+
+            if let Some(block) = self.release_block(block, free_lines, holes, &mut stats) {
+                self.full_blocks.push(block);
+            }
+        }
+
+        self.finish_sweep(&stats);
+    }
+
+    /// Makes sure mutators have at least one block to allocate into after a collection, falling
+    /// back on the defrag reserve before giving up.
+    pub fn ensure_usable_blocks(&self) {
+        if !self.usable_blocks.is_empty() {
+            return;
         }
 
-        debug_assert!(full_blocks + usable_blocks == self.total_blocks);
+        while let Some(block) = steal_block(&self.defrag_reserve) {
+            self.reserved_blocks.fetch_sub(1, Ordering::SeqCst);
+            self.usable_blocks.push(block);
+        }
+
+        if self.usable_blocks.is_empty() {
+            panic!("Out of memory in Immix Space");
+        }
     }
 
     pub fn start(&self) -> Address {
@@ -187,6 +928,9 @@ impl ImmixSpace {
     pub fn end(&self) -> Address {
         self.end
     }
+    pub fn geometry(&self) -> BlockGeometry {
+        self.geometry
+    }
 
     pub fn line_mark_table(&self) -> &LineMarkTable {
         &self.line_mark_table
@@ -196,24 +940,271 @@ impl ImmixSpace {
     pub fn addr_in_space(&self, addr: Address) -> bool {
         addr >= self.start && addr < self.end
     }
+
+    pub fn is_generational(&self) -> bool {
+        self.nursery_start < self.end
+    }
+
+    #[inline(always)]
+    pub fn in_nursery(&self, addr: Address) -> bool {
+        addr >= self.nursery_start && addr < self.end
+    }
+
+    /// Finds the nursery object containing `addr`, which may point anywhere inside it. Optimised
+    /// code can hold on to nothing but the address of a field of an object it is still using, so
+    /// ambiguous roots pointing into young objects have to keep them from being moved.
+    pub fn find_young_object(&self, addr: Address) -> Option<ObjectReference> {
+        if !self.in_nursery(addr) {
+            return None;
+        }
+
+        let word = size_of::<*mut ()>();
+        let mut cursor = addr.as_usize() & !(word - 1);
+        // small objects are smaller than a line, and objects never straddle blocks. Pointers past
+        // the first line of a medium object are not recognised
+        let limit = cursor
+            .saturating_sub(self.geometry.bytes_in_line())
+            .max(cursor & !(self.geometry.bytes_in_block() - 1));
+
+        loop {
+            let obj = unsafe { Address::from_ptr(cursor as *const u8).to_object_reference() };
+            let entry =
+                unsafe { objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start, obj) };
+            if entry != 0 {
+                let is_object = self.object_starts.is_set(obj.to_address());
+                return (is_object && obj.to_address().plus(self.object_size(obj)) > addr)
+                    .then_some(obj);
+            }
+
+            if cursor == limit {
+                return None;
+            }
+            cursor -= word;
+        }
+    }
+
+    /// The end of the mature space, where the nursery starts (the end of the space without one).
+    pub fn nursery_start(&self) -> Address {
+        self.nursery_start
+    }
+
+    /// Checks if the running trace is a minor collection, which only copies objects out of the
+    /// nursery and leaves the mature space alone.
+    #[inline(always)]
+    pub fn is_minor_gc(&self) -> bool {
+        self.minor_gc.load(Ordering::Relaxed)
+    }
+
+    pub fn set_minor_gc(&self, minor_gc: bool) {
+        self.minor_gc.store(minor_gc, Ordering::SeqCst);
+    }
+
+    /// Pins `obj` until `unpin` is called. Pinned objects are never moved by the collector, and are
+    /// kept alive as roots. Pins nest, so independent owners can pin the same object: it stays
+    /// pinned until every `pin` has been matched by an `unpin`. An object can be pinned 255 times
+    /// at once.
+    pub fn pin(&self, obj: ObjectReference) {
+        debug_assert!(unsafe {
+            objectmodel::is_object_start(self.object_starts.as_mut_ptr(), self.start, obj)
+        });
+        self.pin_map
+            .fetch_update(obj.to_address(), |x| x.checked_add(1))
+            .expect("object pinned too often");
+    }
+
+    /// Releases one pin of `obj`, which must be pinned.
+    pub fn unpin(&self, obj: ObjectReference) {
+        self.pin_map
+            .fetch_update(obj.to_address(), |x| x.checked_sub(1))
+            .expect("object is not pinned");
+    }
+
+    #[inline(always)]
+    pub fn is_pinned(&self, obj: ObjectReference) -> bool {
+        self.pin_map.load(obj.to_address()) != 0
+    }
+
+    /// Every object which is currently pinned.
+    pub fn pinned_objects(&self) -> Vec<ObjectReference> {
+        self.pin_map
+            .iter_nonzero()
+            .map(|x| unsafe { x.to_object_reference() })
+            .collect()
+    }
+
+    /// Switches the space to sticky mark bits, so that only every `full_gc_interval`th collection
+    /// traces the whole heap. The others are nursery collections, which rely on mutators storing
+    /// references through `ImmixMutatorLocal::write_ref`. Must be called before any mutator starts
+    /// allocating.
+    pub fn enable_sticky_immix(&self, full_gc_interval: usize) {
+        assert!(
+            !self.is_generational() && !self.rc.is_enabled() && !self.concurrent.is_enabled(),
+            "sticky mark bits can not be combined with a nursery, reference counting or concurrent marking"
+        );
+        self.sticky.enable(full_gc_interval);
+    }
+
+    /// Sets the number of GC threads tracing the space, one per CPU by default. Must be called
+    /// before the first collection.
+    pub fn set_gc_threads(&self, n_gcthreads: usize) {
+        self.gc_workers.set_n_workers(n_gcthreads);
+    }
+
+    /// Sets how many objects each GC thread keeps in its prefetch buffer: objects popped from the
+    /// mark queue wait there while their memory and alloc map entries are fetched into the cache,
+    /// and are scanned once that many more have been popped. The default, 0, scans each object
+    /// straight away: the hardware already fetches objects allocated one after the other well
+    /// enough, so prefetching only pays off when live objects are scattered across the heap.
+    /// Other threads can not steal from the buffer, so it is emptied back into the mark queue
+    /// whenever one of them runs out of work, and it holds at most `MAX_TRACE_PREFETCH` objects.
+    pub fn set_trace_prefetch(&self, depth: usize) {
+        assert!(
+            depth <= MAX_TRACE_PREFETCH,
+            "the prefetch buffer holds at most {} objects",
+            MAX_TRACE_PREFETCH
+        );
+        self.trace_prefetch.store(depth, Ordering::Relaxed);
+    }
+
+    pub fn trace_prefetch(&self) -> usize {
+        self.trace_prefetch.load(Ordering::Relaxed)
+    }
+
+    /// Chooses whether mutators fill recyclable blocks before free ones (the default), or the
+    /// other way around. Medium objects which do not fit in the current hole go to free blocks
+    /// either way.
+    pub fn set_block_policy(&self, policy: BlockPolicy) {
+        self.usable_blocks.set_policy(policy);
+    }
+
+    #[inline(always)]
+    pub(super) fn block_end(&self, addr: Address) -> Address {
+        addr.plus(1).align_up(self.geometry.bytes_in_block())
+    }
+
+    /// Marks the lines of an object live for the running trace. Small objects may spill into the
+    /// line after the one they start in, so both are marked. Medium objects are looked up in the
+    /// medium object bits, and every line they cover is marked.
+    #[inline(always)]
+    pub fn mark_object_lines(&self, obj: ObjectReference) {
+        let addr = obj.to_address();
+        if self.medium_objects.is_set(addr) {
+            self.line_mark_table
+                .mark_lines_live(addr, addr.plus(self.object_size(obj)));
+        } else {
+            self.line_mark_table.mark_line_live(addr);
+        }
+    }
+
+    /// The lines which reference counting keeps for a live object: the one it starts in, and every
+    /// other line of a medium object.
+    pub fn object_lines(&self, addr: Address) -> impl Iterator<Item = Address> {
+        let log_bytes_in_line = self.geometry.log_bytes_in_line();
+        let lines = if self.medium_objects.is_set(addr) {
+            let end = addr.plus(self.object_size(unsafe { addr.to_object_reference() }));
+            ((end.as_usize() - 1) >> log_bytes_in_line) - (addr.as_usize() >> log_bytes_in_line) + 1
+        } else {
+            1
+        };
+
+        (0..lines).map(move |i| addr.plus(i << log_bytes_in_line))
+    }
+
+    /// The size of an object allocated in this space (see `objectmodel::get_object_size`).
+    pub fn object_size(&self, obj: ObjectReference) -> usize {
+        debug_assert!(self.addr_in_space(obj.to_address()));
+
+        unsafe {
+            objectmodel::get_object_size(
+                self.alloc_map.as_mut_ptr(),
+                self.start,
+                obj,
+                self.block_end(obj.to_address()),
+            )
+        }
+    }
+
+    /// Counts the objects marked by the current trace and the number of bytes they occupy. This
+    /// visits every word of the lines marked live, so it is only meant for diagnostics.
+    pub fn traced_object_stats(&self) -> (usize, usize) {
+        let mut objects = 0;
+        let mut bytes = 0;
+
+        for line in 0..self.line_mark_table.len() {
+            let mark = self.line_mark_table.get(line);
+            if mark != LineMark::Live {
+                continue;
+            }
+
+            let line_start = self.start.plus(line << self.geometry.log_bytes_in_line());
+            let mut cursor = line_start;
+            while cursor < line_start.plus(self.geometry.bytes_in_line()) {
+                let obj = unsafe { cursor.to_object_reference() };
+                if unsafe {
+                    objectmodel::is_object_start(self.object_starts.as_mut_ptr(), self.start, obj)
+                } && self.trace_map.is_traced(cursor.to_ptr::<()>())
+                {
+                    objects += 1;
+                    bytes += self.object_size(obj);
+                }
+
+                cursor = cursor.plus(size_of::<*mut ()>());
+            }
+        }
+
+        (objects, bytes)
+    }
 }
 
 pub struct ImmixBlock {
     id: usize,
     state: immix::BlockMark,
+    // holes of free lines, as counted by the last sweep
+    holes: usize,
     start: Address,
+    // lines which were free when the block was last swept, and have not been allocated into since
+    free_lines: LineBitmap,
+    // the lines of the block on the blacklist, as of `blacklist_epoch`
+    blacklisted: LineBitmap,
+    blacklist_epoch: u8,
 
     // a segment of the big line mark table in ImmixSpace
     line_mark_table: LineMarkTableSlice,
+    // the matching segment of the space's blacklist
+    blacklist: LineBlacklistSlice,
 }
 
 impl ImmixBlock {
-    pub fn get_next_available_line(&self, cur_line: usize) -> Option<usize> {
-        self.line_mark_table.get_next_available_line(cur_line)
+    /// The lines which can be allocated into: the free lines which are not blacklisted. The
+    /// blacklisted lines are only looked up again once the blacklist has moved on to a new epoch.
+    #[inline(always)]
+    fn available_lines(&mut self) -> LineBitmap {
+        let epoch = self.blacklist.epoch();
+        if epoch != self.blacklist_epoch {
+            self.blacklisted = self.blacklist.lines();
+            self.blacklist_epoch = epoch;
+        }
+
+        self.free_lines.difference(&self.blacklisted)
+    }
+
+    /// Finds the next free line at or after `cur_line` which is not blacklisted.
+    pub fn get_next_available_line(&mut self, cur_line: usize) -> Option<usize> {
+        self.available_lines().next_set(cur_line)
     }
 
-    pub fn get_next_unavailable_line(&self, cur_line: usize) -> usize {
-        self.line_mark_table.get_next_unavailable_line(cur_line)
+    /// Takes the next hole at or after `cur_line` to allocate into, and marks its lines with
+    /// `mark`. Returns the lines of the hole. Blacklisted lines end a hole even if they are free.
+    pub fn take_hole(&mut self, cur_line: usize, mark: LineMark) -> Option<Range<usize>> {
+        let available = self.available_lines();
+        let start = available.next_set(cur_line)?;
+        let end = available.next_unset(start);
+        for line in start..end {
+            self.line_mark_table.set(line, mark);
+        }
+        self.free_lines.remove_range(start..end);
+
+        Some(start..end)
     }
 
     pub fn id(&self) -> usize {
@@ -222,9 +1213,26 @@ impl ImmixBlock {
     pub fn start(&self) -> Address {
         self.start
     }
+    pub fn state(&self) -> immix::BlockMark {
+        self.state
+    }
     pub fn set_state(&mut self, mark: immix::BlockMark) {
         self.state = mark;
     }
+    pub fn holes(&self) -> usize {
+        self.holes
+    }
+    pub fn set_holes(&mut self, holes: usize) {
+        self.holes = holes;
+    }
+    /// Replaces the free lines of the block, once it has been swept. The blacklisted lines are
+    /// looked up again the next time they are needed, so lines read before the blacklist epoch
+    /// wrapped around are never taken for current ones.
+    pub fn set_free_lines(&mut self, free_lines: LineBitmap) {
+        self.free_lines = free_lines;
+        // the blacklist is never at epoch 0
+        self.blacklist_epoch = 0;
+    }
     #[inline(always)]
     pub fn line_mark_table(&self) -> &LineMarkTableSlice {
         &self.line_mark_table
@@ -280,7 +1288,7 @@ impl fmt::Display for ImmixBlock {
         )?;
 
         write!(f, "[")?;
-        for i in 0..immix::LINES_IN_BLOCK {
+        for i in 0..self.line_mark_table.len() {
             write!(f, "{:?},", self.line_mark_table.get(i))?;
         }
         write!(f, "]")
@@ -1,102 +1,145 @@
-use crate::common::Address;
-use crate::heap::immix;
+use crate::common::{Address, SideMetadata};
+use crate::heap::immix::geometry::MAX_LOG_LINES_IN_BLOCK;
+use crate::heap::immix::BlockGeometry;
 
-use std::alloc::{GlobalAlloc, Layout, System};
 use std::fmt::{self, Debug, Formatter};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU8, Ordering};
 
+/// The state of a line, as seen from the current epoch (see `LineMarkTable`).
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum LineMark {
-    Free,
+    /// Marked by the running collection, or allocated into while it marks concurrently.
     Live,
+    /// Allocated into since the last sweep.
     FreshAlloc,
-    ConservLive,
+    /// Kept by the last sweep. The lines of a block which are not free are all in this state
+    /// between collections.
     PrevLive,
+    Free,
 }
 
+// never used since the space was created. Side metadata starts out zeroed
+const NEVER_USED: u8 = 0;
+const FRESH_ALLOC: u8 = u8::MAX;
+const FIRST_EPOCH: u8 = 1;
+// the lines of a block are reset before the marking epoch could reach `FRESH_ALLOC`
+const LAST_EPOCH: u8 = FRESH_ALLOC - 1;
+
 // this table will be accessed through unsafe raw pointers. since Rust doesn't provide a data structure for such guarantees:
 // 1. Non-overlapping segments of this table may be accessed concurrently from different mutator threads
 // 2. One element may be written into at the same time by different gc threads during tracing
 
-#[derive(Clone)]
+/// A mark byte for every line of the space. Rather than an enum which sweeping has to reset, each
+/// mark is the epoch in which the line was last found live, like in the Immix paper. Sweeping a
+/// block advances its epoch, so lines which were not marked again become free without being
+/// written to, and finding free lines is a single comparison: a line is free if its mark is older
+/// than the epoch of its block. Tracing marks lines with the next epoch, and allocation marks them
+/// as fresh, which is never free.
+///
+/// Every block keeps its own epoch, so blocks can be swept at different times: the nursery apart
+/// from the mature space, or concurrently with mutators. The lines of a block are reset every 253
+/// sweeps, to let its epoch start over.
 pub struct LineMarkTable {
     space_start: Address,
-    ptr: *mut LineMark,
+    geometry: BlockGeometry,
+    ptr: *const AtomicU8,
     len: usize,
+    // the epoch of every block
+    epochs: SideMetadata<8>,
 }
 
 impl LineMarkTable {
-    pub fn new(space_start: Address, space_end: Address) -> LineMarkTable {
-        let line_mark_table_len = space_end.diff(space_start) / immix::BYTES_IN_LINE;
-        let line_mark_table = {
-            // TODO: This could likely be replaced with a Vec
-            let layout = Layout::array::<LineMark>(line_mark_table_len).unwrap();
-            let ret = unsafe { System.alloc(layout) as *mut LineMark };
-            let mut cursor = ret;
-
-            for _ in 0..line_mark_table_len {
-                unsafe {
-                    *cursor = LineMark::Free;
-                }
-                cursor = unsafe { cursor.offset(1) };
-            }
-
-            ret
-        };
+    pub fn new(
+        space_start: Address,
+        space_end: Address,
+        geometry: BlockGeometry,
+        marks: SideMetadata<8>,
+        epochs: SideMetadata<8>,
+    ) -> LineMarkTable {
+        let mut block = space_start;
+        while block < space_end {
+            epochs.store(block, FIRST_EPOCH);
+            block = block.plus(geometry.bytes_in_block());
+        }
 
         LineMarkTable {
             space_start,
-            ptr: line_mark_table,
-            len: line_mark_table_len,
+            geometry,
+            ptr: marks.as_mut_ptr() as *const AtomicU8,
+            len: space_end.diff(space_start) >> geometry.log_bytes_in_line(),
+            epochs,
         }
     }
 
     pub fn take_slice(&mut self, start: usize, len: usize) -> LineMarkTableSlice {
+        let lines_in_block = self.geometry.lines_in_block();
+        debug_assert!(start.is_multiple_of(lines_in_block) && len == lines_in_block);
+        let block = start >> self.geometry.log_lines_in_block();
+
         LineMarkTableSlice {
             ptr: unsafe { self.ptr.add(start) },
+            epoch: unsafe { self.epochs.as_mut_ptr().add(block) } as *const AtomicU8,
             len,
         }
     }
 
     #[inline(always)]
-    #[allow(dead_code)]
-    fn get(&self, index: usize) -> LineMark {
-        debug_assert!(index <= self.len);
-        unsafe { *self.ptr.add(index) }
+    fn mark(&self, index: usize) -> &AtomicU8 {
+        debug_assert!(index < self.len);
+        unsafe { &*self.ptr.add(index) }
     }
 
     #[inline(always)]
-    fn set(&self, index: usize, value: LineMark) {
-        debug_assert!(index <= self.len);
-        unsafe { *self.ptr.add(index) = value };
+    pub fn get(&self, index: usize) -> LineMark {
+        let line = self
+            .space_start
+            .plus(index << self.geometry.log_bytes_in_line());
+        line_mark(
+            self.mark(index).load(Ordering::Relaxed),
+            self.epochs.load(line),
+        )
     }
 
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Marks the line of `addr` live in the next epoch, along with the line after it in the same
+    /// block, which the object may spill into.
     #[inline(always)]
     pub fn mark_line_live(&self, addr: Address) {
-        let line_table_index = addr.diff(self.space_start) >> immix::LOG_BYTES_IN_LINE;
+        let line_table_index = addr.diff(self.space_start) >> self.geometry.log_bytes_in_line();
+        let mark = self.epochs.load(addr) + 1;
 
-        self.set(line_table_index, LineMark::Live);
+        self.mark(line_table_index).store(mark, Ordering::Relaxed);
 
-        if line_table_index < self.len - 1 {
-            self.set(line_table_index + 1, LineMark::ConservLive);
+        let lines_in_block = self.geometry.lines_in_block();
+        if line_table_index % lines_in_block < lines_in_block - 1 {
+            self.mark(line_table_index + 1)
+                .store(mark, Ordering::Relaxed);
         }
     }
 
+    /// Marks every line of `start..end` live in the next epoch, for objects larger than a line.
+    /// Objects never straddle blocks, so the lines share the epoch of `start`.
     #[inline(always)]
-    pub fn mark_line_live2(&self, space_start: Address, addr: Address) {
-        let line_table_index = addr.diff(space_start) >> immix::LOG_BYTES_IN_LINE;
-
-        self.set(line_table_index, LineMark::Live);
+    pub fn mark_lines_live(&self, start: Address, end: Address) {
+        let log_bytes_in_line = self.geometry.log_bytes_in_line();
+        let first = start.diff(self.space_start) >> log_bytes_in_line;
+        let last = (end.diff(self.space_start) - 1) >> log_bytes_in_line;
+        let mark = self.epochs.load(start) + 1;
 
-        if line_table_index < self.len - 1 {
-            self.set(line_table_index + 1, LineMark::ConservLive);
+        for index in first..=last {
+            self.mark(index).store(mark, Ordering::Relaxed);
         }
     }
-}
 
-impl Drop for LineMarkTable {
-    fn drop(&mut self) {
-        let layout = Layout::array::<LineMark>(self.len).unwrap();
-        unsafe { System.dealloc(self.ptr as *mut u8, layout) }
+    #[inline(always)]
+    pub fn mark_line_live2(&self, space_start: Address, addr: Address) {
+        debug_assert!(space_start == self.space_start);
+        self.mark_line_live(addr);
     }
 }
 
@@ -106,49 +149,76 @@ impl Debug for LineMarkTable {
     }
 }
 
+#[inline(always)]
+fn line_mark(mark: u8, epoch: u8) -> LineMark {
+    match mark {
+        FRESH_ALLOC => LineMark::FreshAlloc,
+        x if x == epoch + 1 => LineMark::Live,
+        x if x == epoch => LineMark::PrevLive,
+        _ => LineMark::Free,
+    }
+}
+
+/// The part of the line mark table covering a single block, along with the epoch of the block.
 #[derive(Clone)]
 pub struct LineMarkTableSlice {
-    ptr: *mut LineMark,
+    ptr: *const AtomicU8,
+    epoch: *const AtomicU8,
     len: usize,
 }
 
 impl LineMarkTableSlice {
+    #[inline(always)]
+    fn mark(&self, index: usize) -> &AtomicU8 {
+        debug_assert!(index < self.len);
+        unsafe { &*self.ptr.add(index) }
+    }
+
+    #[inline(always)]
+    fn epoch(&self) -> u8 {
+        unsafe { (*self.epoch).load(Ordering::Relaxed) }
+    }
+
     #[inline(always)]
     pub fn get(&self, index: usize) -> LineMark {
-        debug_assert!(index <= self.len);
-        unsafe { *self.ptr.add(index) }
+        line_mark(self.mark(index).load(Ordering::Relaxed), self.epoch())
     }
     #[inline(always)]
     pub fn set(&mut self, index: usize, value: LineMark) {
-        debug_assert!(index <= self.len);
-        unsafe { *self.ptr.add(index) = value };
+        let mark = match value {
+            LineMark::Live => self.epoch() + 1,
+            LineMark::FreshAlloc => FRESH_ALLOC,
+            LineMark::PrevLive => self.epoch(),
+            LineMark::Free => NEVER_USED,
+        };
+        self.mark(index).store(mark, Ordering::Relaxed);
     }
     #[inline(always)]
     pub fn len(&self) -> usize {
         self.len
     }
 
-    pub fn get_next_available_line(&self, cur_line: usize) -> Option<usize> {
-        let mut i = cur_line;
-        while i < self.len {
-            match self.get(i) {
-                LineMark::Free => return Some(i),
-                _ => i += 1,
-            }
+    /// Moves the block on to the next epoch, once it has been swept. The lines marked by the last
+    /// trace are now the live ones, and every other line which was not allocated into is free.
+    pub fn advance(&mut self) {
+        let epoch = unsafe { &*self.epoch };
+        let next = epoch.load(Ordering::Relaxed) + 1;
+
+        if next < LAST_EPOCH {
+            epoch.store(next, Ordering::Relaxed);
+            return;
         }
-        None
-    }
 
-    // TODO: This looks like it should return an Option
-    pub fn get_next_unavailable_line(&self, cur_line: usize) -> usize {
-        let mut i = cur_line;
-        while i < self.len {
-            match self.get(i) {
-                LineMark::Free => i += 1,
-                _ => return i,
+        // start over from the first epoch, with every line which is not live forgotten
+        for index in 0..self.len {
+            let mark = self.mark(index);
+            match mark.load(Ordering::Relaxed) {
+                FRESH_ALLOC => {}
+                x if x == next => mark.store(FIRST_EPOCH, Ordering::Relaxed),
+                _ => mark.store(NEVER_USED, Ordering::Relaxed),
             }
         }
-        i
+        epoch.store(FIRST_EPOCH, Ordering::Relaxed);
     }
 }
 
@@ -157,3 +227,350 @@ impl Debug for LineMarkTableSlice {
         write!(f, "{:p}", self.ptr)
     }
 }
+
+const BITMAP_WORDS: usize = (1 << MAX_LOG_LINES_IN_BLOCK) / u64::BITS as usize;
+
+/// A bit for every line of a block. Blocks keep the lines they can allocate into in one of these,
+/// so the hole search tests a word of lines at a time instead of loading every line mark. The
+/// bitmap is sized for the largest blocks, but only the words covering `len` lines are used.
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct LineBitmap {
+    words: [u64; BITMAP_WORDS],
+    len: usize,
+}
+
+impl LineBitmap {
+    /// A bitmap of `len` lines, none of them set.
+    pub fn empty(len: usize) -> LineBitmap {
+        debug_assert!(len <= BITMAP_WORDS * 64);
+        LineBitmap {
+            words: [0; BITMAP_WORDS],
+            len,
+        }
+    }
+
+    /// A bitmap of `len` lines, all of them set.
+    pub fn full(len: usize) -> LineBitmap {
+        let mut bitmap = LineBitmap::empty(len);
+        bitmap.insert_range(0..len);
+        bitmap
+    }
+
+    #[inline(always)]
+    pub fn insert(&mut self, line: usize) {
+        debug_assert!(line < self.len);
+        self.words[line / 64] |= 1 << (line % 64);
+    }
+
+    pub fn insert_range(&mut self, lines: Range<usize>) {
+        for line in lines {
+            self.insert(line);
+        }
+    }
+
+    /// Clears the lines of `lines`, a word at a time.
+    #[inline(always)]
+    pub fn remove_range(&mut self, lines: Range<usize>) {
+        let mut line = lines.start;
+        while line < lines.end {
+            let bit = line % 64;
+            let len = (64 - bit).min(lines.end - line);
+            let mask = (u64::MAX >> (64 - len)) << bit;
+            self.words[line / 64] &= !mask;
+            line += len;
+        }
+    }
+
+    /// The lines of `self` which are not set in `other`.
+    #[inline(always)]
+    pub fn difference(&self, other: &LineBitmap) -> LineBitmap {
+        debug_assert!(self.len == other.len);
+        let mut words = self.words;
+        for (word, other) in words
+            .iter_mut()
+            .zip(other.words.iter())
+            .take(self.words_used())
+        {
+            *word &= !other;
+        }
+        LineBitmap {
+            words,
+            len: self.len,
+        }
+    }
+
+    /// Finds the first line at or after `from` which is set.
+    #[inline(always)]
+    pub fn next_set(&self, from: usize) -> Option<usize> {
+        self.next(from, 0)
+    }
+
+    /// Finds the first line at or after `from` which is not set, or the end of the block.
+    #[inline(always)]
+    pub fn next_unset(&self, from: usize) -> usize {
+        self.next(from, u64::MAX).unwrap_or(self.len)
+    }
+
+    #[inline(always)]
+    fn words_used(&self) -> usize {
+        self.len.div_ceil(64)
+    }
+
+    // scans the words xor'ed with `flip` for the first set bit at or after `from`
+    #[inline(always)]
+    fn next(&self, from: usize, flip: u64) -> Option<usize> {
+        let words = self.words_used();
+        let mut index = from / 64;
+        if index >= words {
+            return None;
+        }
+
+        let mut word = (self.words[index] ^ flip) & (u64::MAX << (from % 64));
+        loop {
+            if word != 0 {
+                let line = index * 64 + word.trailing_zeros() as usize;
+                return (line < self.len).then_some(line);
+            }
+
+            index += 1;
+            if index == words {
+                return None;
+            }
+            word = self.words[index] ^ flip;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|&x| x == 0)
+    }
+
+    /// Number of lines set.
+    pub fn count(&self) -> usize {
+        self.words.iter().map(|x| x.count_ones() as usize).sum()
+    }
+
+    /// Number of runs of set lines.
+    pub fn holes(&self) -> usize {
+        // a hole starts at every set bit whose previous bit is unset, across word boundaries too
+        let mut carry = 0;
+        let mut holes = 0;
+        for &word in self.words.iter() {
+            holes += (word & !((word << 1) | carry)).count_ones() as usize;
+            carry = word >> 63;
+        }
+        holes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // lengths of a partial word, whole words, and the largest blocks
+    const LENS: [usize; 6] = [1, 20, 64, 100, 128, 512];
+
+    fn bitmap(len: usize, lines: &[usize]) -> LineBitmap {
+        let mut bitmap = LineBitmap::empty(len);
+        for &line in lines {
+            bitmap.insert(line);
+        }
+        bitmap
+    }
+
+    // the same searches, a line at a time
+    fn slow_next(set: &[bool], from: usize, value: bool) -> Option<usize> {
+        (from..set.len()).find(|&x| set[x] == value)
+    }
+
+    fn slow_holes(set: &[bool]) -> usize {
+        (0..set.len())
+            .filter(|&x| set[x] && (x == 0 || !set[x - 1]))
+            .count()
+    }
+
+    #[test]
+    fn searches_agree_with_a_line_by_line_scan() {
+        for len in LENS {
+            // a few patterns, with runs across word boundaries
+            let patterns: [&dyn Fn(usize) -> bool; 5] = [
+                &|_| false,
+                &|_| true,
+                &|x| x % 3 == 0,
+                &|x| (60..70).contains(&x) || x + 1 == len,
+                &|x| x % 64 != 63,
+            ];
+            for pattern in patterns {
+                let set: Vec<bool> = (0..len).map(pattern).collect();
+                let lines: Vec<usize> = (0..len).filter(|&x| set[x]).collect();
+                let bitmap = bitmap(len, &lines);
+
+                for from in 0..len + 2 {
+                    assert_eq!(bitmap.next_set(from), slow_next(&set, from, true));
+                    assert_eq!(
+                        bitmap.next_unset(from),
+                        slow_next(&set, from, false).unwrap_or(len),
+                        "next_unset({}) of {} lines",
+                        from,
+                        len
+                    );
+                }
+                assert_eq!(bitmap.count(), lines.len());
+                assert_eq!(bitmap.holes(), slow_holes(&set), "holes of {} lines", len);
+                assert_eq!(bitmap.is_empty(), lines.is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn full_bitmaps_have_nothing_unset_past_the_end() {
+        for len in LENS {
+            let full = LineBitmap::full(len);
+            assert_eq!(full.count(), len);
+            assert_eq!(full.holes(), 1);
+            assert_eq!(full.next_set(0), Some(0));
+            assert_eq!(full.next_set(len - 1), Some(len - 1));
+            assert_eq!(full.next_set(len), None);
+            // the bits past `len` are clear, and must not be taken for unset lines
+            assert_eq!(full.next_unset(0), len);
+            assert_eq!(full.next_unset(len - 1), len);
+        }
+    }
+
+    #[test]
+    fn remove_range_clears_only_the_range() {
+        for len in LENS {
+            for (start, end) in [
+                (0, 1),
+                (0, 64),
+                (1, 63),
+                (5, 70),
+                (63, 65),
+                (64, 128),
+                (0, 512),
+            ] {
+                let (start, end) = (start.min(len), end.min(len));
+                let mut bitmap = LineBitmap::full(len);
+                bitmap.remove_range(start..end);
+
+                for line in 0..len {
+                    assert_eq!(
+                        bitmap.next_set(line) == Some(line),
+                        !(start..end).contains(&line),
+                        "line {} after removing {}..{} of {}",
+                        line,
+                        start,
+                        end,
+                        len
+                    );
+                }
+                assert_eq!(bitmap.count(), len - (end - start));
+            }
+        }
+    }
+
+    #[test]
+    fn difference_keeps_lines_only_in_self() {
+        let free = bitmap(512, &[0, 1, 2, 63, 64, 65, 300, 511]);
+        let blacklisted = bitmap(512, &[1, 64, 511, 400]);
+        assert_eq!(
+            free.difference(&blacklisted),
+            bitmap(512, &[0, 2, 63, 65, 300])
+        );
+    }
+
+    #[test]
+    fn holes_carry_across_words() {
+        assert_eq!(bitmap(128, &[63, 64]).holes(), 1);
+        assert_eq!(bitmap(128, &[62, 64]).holes(), 2);
+        assert_eq!(bitmap(512, &[0, 127, 128, 255, 256, 511]).holes(), 4);
+    }
+
+    // a block of `LINES` lines, with marks and an epoch of its own
+    const LINES: usize = 8;
+
+    struct Block {
+        marks: [AtomicU8; LINES],
+        epoch: AtomicU8,
+    }
+
+    impl Block {
+        fn new() -> Block {
+            Block {
+                marks: Default::default(),
+                epoch: AtomicU8::new(FIRST_EPOCH),
+            }
+        }
+
+        fn slice(&self) -> LineMarkTableSlice {
+            LineMarkTableSlice {
+                ptr: self.marks.as_ptr(),
+                epoch: &self.epoch,
+                len: LINES,
+            }
+        }
+    }
+
+    fn marks(slice: &LineMarkTableSlice) -> Vec<LineMark> {
+        (0..slice.len()).map(|x| slice.get(x)).collect()
+    }
+
+    #[test]
+    fn advancing_frees_the_lines_not_marked_again() {
+        use LineMark::*;
+
+        let block = Block::new();
+        let mut slice = block.slice();
+        assert_eq!(marks(&slice), [Free; LINES]);
+
+        let before = [Live, Live, PrevLive, PrevLive, FreshAlloc, Free, Live, Free];
+        for (line, &mark) in before.iter().enumerate() {
+            slice.set(line, mark);
+        }
+        assert_eq!(marks(&slice), before);
+
+        slice.advance();
+        assert_eq!(
+            marks(&slice),
+            [PrevLive, PrevLive, Free, Free, FreshAlloc, Free, PrevLive, Free]
+        );
+    }
+
+    #[test]
+    fn advancing_past_the_last_epoch_starts_over() {
+        use LineMark::*;
+
+        let block = Block::new();
+        let mut slice = block.slice();
+        slice.set(0, FreshAlloc);
+        slice.set(1, PrevLive);
+
+        // line 1 stays live throughout, line 2 dies half way, and line 3 was live long ago
+        slice.set(3, Live);
+        slice.advance();
+        for round in 0..3 * LAST_EPOCH as usize {
+            slice.set(1, Live);
+            if round < LAST_EPOCH as usize / 2 {
+                slice.set(2, Live);
+            }
+            slice.advance();
+
+            let epoch = block.epoch.load(Ordering::Relaxed);
+            assert!(
+                (FIRST_EPOCH..LAST_EPOCH).contains(&epoch),
+                "epoch {}",
+                epoch
+            );
+            let expected_2 = if round < LAST_EPOCH as usize / 2 {
+                PrevLive
+            } else {
+                Free
+            };
+            assert_eq!(
+                marks(&slice)[..4],
+                [FreshAlloc, PrevLive, expected_2, Free],
+                "round {}",
+                round
+            );
+        }
+    }
+}
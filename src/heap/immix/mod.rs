@@ -1,20 +1,36 @@
+mod barrier;
+mod blacklist;
+mod block_pool;
+mod card_table;
+mod compact;
+mod defrag;
+mod geometry;
 mod immix_mutator;
 mod immix_space;
 mod line_mark;
+mod rc;
+mod satb;
+mod soft_dirty;
+mod sticky;
+mod sweep;
 
+pub use self::barrier::WriteBarrier;
+pub use self::block_pool::BlockPolicy;
+pub use self::card_table::{CARD_DIRTY, LOG_BYTES_IN_CARD};
+pub use self::defrag::CopyAllocator;
+pub use self::geometry::BlockGeometry;
 pub use self::immix_mutator::MUTATORS;
 pub use self::immix_mutator::N_MUTATORS;
+pub use self::satb::{MarkingBudget, SATB_BUFFER_LEN};
 pub use immix_mutator::ImmixMutatorLocal;
 pub use immix_space::ImmixSpace;
 
-const LOG_BYTES_IN_LINE: usize = 8;
-const BYTES_IN_LINE: usize = 1 << LOG_BYTES_IN_LINE;
-const LOG_BYTES_IN_BLOCK: usize = 16;
-const BYTES_IN_BLOCK: usize = 1 << LOG_BYTES_IN_BLOCK;
-const LINES_IN_BLOCK: usize = 1 << (LOG_BYTES_IN_BLOCK - LOG_BYTES_IN_LINE);
-
 #[derive(PartialEq, Eq, Debug, Copy, Clone)]
 pub enum BlockMark {
-    Usable,
+    /// Not a single line in use.
+    Free,
+    /// Some lines in use, with holes of free lines between them.
+    Recyclable,
+    /// No free line left until the next sweep.
     Full,
 }
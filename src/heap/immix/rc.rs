@@ -0,0 +1,329 @@
+use crate::common::{Address, ObjectReference, SideMetadata};
+use crate::heap::immix::{ImmixSpace, WriteBarrier};
+use crate::objectmodel;
+
+use log::trace;
+use parking_lot::Mutex;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// counts stick once they reach this, and only a backup trace can reclaim the object
+const STUCK: u8 = u8::MAX;
+
+/// Reference counts for RC Immix. Every old object has a count of the references to it from other
+/// objects, plus one while a root referred to it at the last collection (root increments are
+/// deferred to the next collection, so roots never have to be counted as they change). Every line
+/// has a count of the live objects starting in it, and becomes free once that drops to zero.
+///
+/// Counts are only updated at collections. The write barrier logs an object the first time it is
+/// modified, together with the objects it referred to until then (see `ImmixSpace::rc_log`). The
+/// collection then increments everything logged objects refer to now, and decrements what they
+/// referred to before (coalescing all the stores in between). Objects allocated since the last
+/// collection have no count yet: they are only counted if something refers to them, which in turn
+/// counts everything they refer to. The rest die without ever being looked at.
+///
+/// Garbage cycles never drop to zero, and neither do counts which got stuck, so every
+/// `backup_trace_interval`th collection (or when the space is exhausted) is a full trace which
+/// recounts everything from scratch.
+pub struct RefCounts {
+    enabled: AtomicBool,
+    backup_trace_interval: AtomicUsize,
+    // collections since the last backup trace
+    collections: AtomicUsize,
+
+    counts: SideMetadata<8>,
+    line_counts: SideMetadata<8>,
+
+    // objects referred to by the logged objects before they were modified
+    decrements: Mutex<Vec<Address>>,
+    // roots counted at the last collection, to be decremented at the next one
+    root_increments: Mutex<Vec<ObjectReference>>,
+}
+
+impl RefCounts {
+    pub fn new(counts: SideMetadata<8>, line_counts: SideMetadata<8>) -> RefCounts {
+        RefCounts {
+            enabled: AtomicBool::new(false),
+            backup_trace_interval: AtomicUsize::new(0),
+            collections: AtomicUsize::new(0),
+            counts,
+            line_counts,
+            decrements: Mutex::new(Vec::new()),
+            root_increments: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn enable(&self, backup_trace_interval: usize) {
+        assert!(
+            backup_trace_interval > 0,
+            "backup_trace_interval must be at least 1"
+        );
+
+        self.backup_trace_interval
+            .store(backup_trace_interval, Ordering::SeqCst);
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Decides whether the collection which just counted references also has to trace the heap.
+    pub fn is_backup_trace_due(&self) -> bool {
+        self.collections.load(Ordering::SeqCst) + 1
+            >= self.backup_trace_interval.load(Ordering::SeqCst)
+    }
+
+    pub fn finish_collection(&self, backup_trace: bool) {
+        if backup_trace {
+            self.collections.store(0, Ordering::SeqCst);
+        } else {
+            self.collections.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[inline(always)]
+    pub fn count(&self, addr: Address) -> u8 {
+        self.counts.load(addr)
+    }
+
+    /// Adds one to the count of the object at `addr`, and returns the count it had before.
+    #[inline(always)]
+    pub fn increment(&self, addr: Address) -> u8 {
+        match self
+            .counts
+            .fetch_update(addr, |x| (x != STUCK).then(|| x + 1))
+        {
+            Ok(x) | Err(x) => x,
+        }
+    }
+
+    /// Takes one from the count of the object at `addr`, and returns whether that left it at zero.
+    /// Zero and stuck counts are left alone.
+    #[inline(always)]
+    pub fn decrement(&self, addr: Address) -> bool {
+        match self.counts.load(addr) {
+            0 | STUCK => false,
+            x => {
+                self.counts.store(addr, x - 1);
+                x == 1
+            }
+        }
+    }
+
+    /// Counts a live object starting at `addr` in its line.
+    #[inline(always)]
+    pub fn count_line(&self, addr: Address) {
+        let _ = self
+            .line_counts
+            .fetch_update(addr, |x| Some(x.wrapping_add(1)));
+    }
+
+    #[inline(always)]
+    pub fn uncount_line(&self, addr: Address) {
+        let _ = self
+            .line_counts
+            .fetch_update(addr, |x| Some(x.wrapping_sub(1)));
+    }
+
+    /// The lines in which at least one live object starts.
+    pub fn live_lines(&self) -> impl Iterator<Item = Address> + '_ {
+        self.line_counts.iter_nonzero()
+    }
+
+    pub fn push_decrements<I: Iterator<Item = Address>>(&self, objects: I) {
+        self.decrements.lock().extend(objects);
+    }
+
+    /// Forgets the counts of every word in `start..end`, once that memory is freed.
+    pub fn clear(&self, start: Address, end: Address) {
+        self.counts.clear_range(start, end);
+        self.line_counts.clear_range(start, end);
+    }
+
+    /// Forgets every count before a backup trace, which recounts them all.
+    pub fn reset(&self) {
+        self.counts.clear_all();
+        self.line_counts.clear_all();
+        self.decrements.lock().clear();
+        self.root_increments.lock().clear();
+    }
+
+    /// Moves the counts of the objects moved by compaction from their old to their new addresses.
+    /// Every line count is forgotten, for the caller to recount the lines of the objects once they
+    /// are in place.
+    pub fn relocate<I: Iterator<Item = (Address, Address)>>(&self, moves: I) {
+        let counts: Vec<(Address, u8)> = moves.map(|(from, to)| (to, self.count(from))).collect();
+
+        self.counts.clear_all();
+        self.line_counts.clear_all();
+
+        for (to, count) in counts {
+            self.counts.store(to, count);
+        }
+    }
+}
+
+impl ImmixSpace {
+    /// Switches the space to reference counting (RC Immix), with a backup trace every
+    /// `backup_trace_interval` collections to reclaim cycles. Relies on mutators storing references
+    /// through `ImmixMutatorLocal::write_ref`, and always uses the object barrier. Must be called
+    /// before any mutator is created.
+    pub fn enable_ref_counting(&self, backup_trace_interval: usize) {
+        assert!(
+            !self.is_generational()
+                && !self.sticky.is_enabled()
+                && !self.concurrent.is_enabled()
+                && self.remset.barrier() != WriteBarrier::SoftDirty,
+            "reference counting can not be combined with a nursery, sticky mark bits, concurrent marking or soft-dirty tracking"
+        );
+
+        self.set_write_barrier(WriteBarrier::Object);
+        self.rc.enable(backup_trace_interval);
+    }
+
+    /// Slow path of the write barrier in reference counting mode. The objects `obj` refers to are
+    /// recorded before its unlogged bit is cleared, so no other mutator can store into it before
+    /// they are.
+    pub fn rc_log(&self, obj: ObjectReference) {
+        self.remset.log_with(obj.to_address(), || {
+            let encode = unsafe {
+                objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+            };
+            self.rc.push_decrements(
+                objectmodel::ref_slots(encode, obj)
+                    .map(|slot| unsafe { *slot.to_ptr::<Address>() }),
+            );
+        });
+    }
+
+    /// Applies the increments and decrements collected since the last collection, and frees the
+    /// lines left without live objects. `roots` are counted until the next collection. Returns
+    /// true if the space is exhausted (see `sweep`).
+    pub fn rc_collect(self: &Arc<Self>, roots: &[ObjectReference]) -> bool {
+        let logged = self.remset.take();
+
+        let mut decrements = std::mem::take(&mut *self.rc.decrements.lock());
+        let old_roots = std::mem::replace(&mut *self.rc.root_increments.lock(), roots.to_vec());
+        decrements.extend(old_roots.iter().map(|x| x.to_address()));
+
+        // increments go first, so objects which only moved between fields never drop to zero
+        let mut increments: Vec<Address> = roots.iter().map(|x| x.to_address()).collect();
+        for &obj in logged.iter() {
+            let obj = unsafe { obj.to_object_reference() };
+            let encode = unsafe {
+                objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+            };
+            increments.extend(
+                objectmodel::ref_slots(encode, obj)
+                    .map(|slot| unsafe { *slot.to_ptr::<Address>() }),
+            );
+        }
+
+        let (mut incremented, mut new_objects) = (0, 0);
+        while let Some(addr) = increments.pop() {
+            if !self.is_rc_object(addr) {
+                continue;
+            }
+
+            incremented += 1;
+            self.rc.increment(addr);
+            if !self.remset.is_unlogged(addr) {
+                // first reference to a new object: it is old from now on, and counts its referents
+                new_objects += 1;
+                let obj = unsafe { addr.to_object_reference() };
+                let encode = unsafe {
+                    objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+                };
+                self.remset.unlog(obj, encode);
+                for line in self.object_lines(addr) {
+                    self.rc.count_line(line);
+                }
+                increments.extend(
+                    objectmodel::ref_slots(encode, obj)
+                        .map(|slot| unsafe { *slot.to_ptr::<Address>() }),
+                );
+            }
+        }
+
+        let (mut decremented, mut freed) = (0, 0);
+        while let Some(addr) = decrements.pop() {
+            if !self.is_rc_object(addr) {
+                continue;
+            }
+
+            decremented += 1;
+            if self.rc.decrement(addr) {
+                freed += 1;
+                let obj = unsafe { addr.to_object_reference() };
+                let encode = unsafe {
+                    objectmodel::get_ref_byte(self.alloc_map.as_mut_ptr(), self.start(), obj)
+                };
+                decrements.extend(
+                    objectmodel::ref_slots(encode, obj)
+                        .map(|slot| unsafe { *slot.to_ptr::<Address>() }),
+                );
+
+                // the object is gone, but the memory stays in use until the whole line is free
+                for line in self.object_lines(addr) {
+                    self.rc.uncount_line(line);
+                }
+                self.alloc_map.store(addr, objectmodel::HOLE_END);
+                self.object_starts.clear(addr);
+                self.medium_objects.clear(addr);
+                self.remset.clear(addr, addr.plus(size_of::<*mut ()>()));
+            }
+        }
+
+        trace!(
+            "rc: {} increments ({} new objects), {} decrements ({} objects freed)",
+            incremented,
+            new_objects,
+            decremented,
+            freed
+        );
+        if cfg!(debug_assertions) {
+            println!(
+                "rc            = {} incs ({} new), {} decs ({} freed)",
+                incremented, new_objects, decremented, freed
+            );
+        }
+
+        for line in self.rc.live_lines() {
+            self.line_mark_table.mark_line_live(line);
+        }
+
+        self.sweep(true)
+    }
+
+    /// Counts `roots` after a backup trace, so they are decremented again at the next collection.
+    pub fn rc_count_roots(&self, roots: &[ObjectReference]) {
+        for root in roots.iter().filter(|x| self.is_rc_object(x.to_address())) {
+            self.rc.increment(root.to_address());
+        }
+
+        *self.rc.root_increments.lock() = roots.to_vec();
+    }
+
+    /// Counts a reference found by a backup trace.
+    #[inline(always)]
+    pub fn rc_count_edge(&self, value: Address) {
+        if self.is_rc_object(value) {
+            self.rc.increment(value);
+        }
+    }
+
+    #[inline(always)]
+    fn is_rc_object(&self, addr: Address) -> bool {
+        self.addr_in_space(addr)
+            && unsafe {
+                objectmodel::is_object_start(
+                    self.object_starts.as_mut_ptr(),
+                    self.start(),
+                    addr.to_object_reference(),
+                )
+            }
+    }
+}
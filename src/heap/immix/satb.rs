@@ -0,0 +1,150 @@
+use crate::common::ObjectReference;
+use crate::heap::immix::{ImmixSpace, WriteBarrier};
+use crate::objectmodel;
+
+use parking_lot::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Entries a mutator collects before handing them to the marking threads.
+pub const SATB_BUFFER_LEN: usize = 256;
+
+/// State of concurrent marking. A short pause takes a snapshot of the roots and starts GC threads
+/// tracing from them while mutators keep running. Mutators then record the old value of every
+/// reference they overwrite (a snapshot-at-the-beginning barrier, see
+/// `ImmixMutatorLocal::write_ref`), so that everything reachable when marking started gets marked
+/// even if the mutators unlink it. Objects allocated while marking are kept by marking their lines
+/// live as they are handed out. Once the marking threads run out of work, a second pause traces
+/// from the recorded references left over and sweeps.
+///
+/// With a `MarkingBudget`, marking is incremental instead: there is no marking thread, and mutators
+/// trace a bounded slice of the heap whenever they take a new block (see `gc::mark_slice`).
+#[derive(Default)]
+pub struct ConcurrentMarking {
+    enabled: AtomicBool,
+    marking: AtomicBool,
+    budget: Mutex<Option<MarkingBudget>>,
+    // references recorded by the barrier and not traced yet
+    buffer: Mutex<Vec<ObjectReference>>,
+    // objects marked by incremental slices but not scanned yet
+    grey: Mutex<Vec<ObjectReference>>,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+/// How much marking a slice of incremental marking may do before returning to the mutator.
+#[derive(Clone, Copy, Debug)]
+pub enum MarkingBudget {
+    /// Stop once this much time has passed.
+    Time(Duration),
+    /// Stop once this many objects have been scanned.
+    Work(usize),
+}
+
+impl ConcurrentMarking {
+    pub fn new() -> ConcurrentMarking {
+        ConcurrentMarking::default()
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_budget(&self, budget: MarkingBudget) {
+        *self.budget.lock() = Some(budget);
+    }
+
+    /// The budget of an incremental slice, or None if marking threads run concurrently instead.
+    pub fn budget(&self) -> Option<MarkingBudget> {
+        *self.budget.lock()
+    }
+
+    #[inline(always)]
+    pub fn is_marking(&self) -> bool {
+        self.marking.load(Ordering::Relaxed)
+    }
+
+    /// The marking flag, read directly by the fast path of the barrier.
+    pub fn marking_flag(&self) -> *const AtomicBool {
+        &self.marking
+    }
+
+    /// Starts a marking cycle, traced by `thread` (or by incremental slices, without one).
+    pub fn start(&self, thread: Option<JoinHandle<()>>) {
+        self.marking.store(true, Ordering::SeqCst);
+        *self.thread.lock() = thread;
+    }
+
+    /// Waits for the marking threads to run out of work, and ends the cycle. Only called while the
+    /// world is stopped, so no more references are recorded afterwards.
+    pub fn finish(&self) {
+        if let Some(thread) = self.thread.lock().take() {
+            thread.join().unwrap();
+        }
+        self.marking.store(false, Ordering::SeqCst);
+    }
+
+    pub fn push(&self, entries: &mut Vec<ObjectReference>) {
+        self.buffer.lock().append(entries);
+    }
+
+    pub fn take(&self) -> Vec<ObjectReference> {
+        std::mem::take(&mut *self.buffer.lock())
+    }
+
+    /// The objects left for incremental slices to scan. Only one slice runs at a time, so returns
+    /// None if another mutator is running one.
+    pub fn try_lock_grey(&self) -> Option<MutexGuard<'_, Vec<ObjectReference>>> {
+        self.grey.try_lock()
+    }
+
+    pub fn take_grey(&self) -> Vec<ObjectReference> {
+        std::mem::take(&mut *self.grey.lock())
+    }
+}
+
+impl ImmixSpace {
+    /// Switches the space to concurrent marking. Relies on mutators storing references through
+    /// `ImmixMutatorLocal::write_ref`. Must be called before any mutator is created.
+    pub fn enable_concurrent_marking(&self) {
+        assert!(
+            !self.is_generational()
+                && !self.sticky.is_enabled()
+                && !self.rc.is_enabled()
+                && self.remset.barrier() != WriteBarrier::SoftDirty,
+            "concurrent marking can not be combined with a nursery, sticky mark bits, reference counting or soft-dirty tracking"
+        );
+        self.concurrent.enable();
+    }
+
+    /// Switches the space to incremental marking, for when concurrent marking threads would not
+    /// get a core of their own. Mutators do the marking themselves, a slice of at most `budget`
+    /// every time they take a new block. Must be called before any mutator is created.
+    pub fn enable_incremental_marking(&self, budget: MarkingBudget) {
+        self.enable_concurrent_marking();
+        self.concurrent.set_budget(budget);
+    }
+
+    /// Checks if the barrier has to record `obj`, overwritten while marking.
+    #[inline(always)]
+    pub fn is_satb_candidate(&self, obj: ObjectReference) -> bool {
+        let addr = obj.to_address();
+        self.addr_in_space(addr)
+            && !self.trace_map.is_traced(addr.to_ptr::<()>())
+            && unsafe {
+                objectmodel::is_object_start(self.object_starts.as_mut_ptr(), self.start(), obj)
+            }
+    }
+
+    /// Takes the references recorded by the barrier which still have to be traced.
+    pub fn take_satb_entries(&self) -> Vec<ObjectReference> {
+        let mut entries = self.concurrent.take();
+        entries.retain(|x| !self.trace_map.is_traced(x.to_address().to_ptr::<()>()));
+        entries
+    }
+}
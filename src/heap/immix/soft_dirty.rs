@@ -0,0 +1,102 @@
+use crate::common::Address;
+use crate::heap::immix::card_table::BYTES_IN_CARD;
+use crate::heap::immix::{ImmixSpace, WriteBarrier};
+
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::mem::size_of;
+use std::os::unix::fs::FileExt;
+
+const LOG_BYTES_IN_PAGE: usize = 12;
+const BYTES_IN_PAGE: usize = 1 << LOG_BYTES_IN_PAGE;
+
+// bit of a pagemap entry set when the page was written to since the soft-dirty bits were cleared
+const SOFT_DIRTY_BIT: u64 = 1 << 55;
+// value written to clear_refs to clear the soft-dirty bits of every page of the process
+const CLEAR_SOFT_DIRTY: &[u8] = b"4";
+
+/// Clears the soft-dirty bit of every page of this process (see
+/// Documentation/admin-guide/mm/soft-dirty.rst in the Linux sources). This is process wide, so
+/// anything else relying on soft-dirty bits (a checkpointing tool, say) sees everything clean too.
+pub fn clear_soft_dirty() -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .open("/proc/self/clear_refs")?
+        .write_all(CLEAR_SOFT_DIRTY)
+}
+
+/// The pages in `start..end` written to since the soft-dirty bits were last cleared.
+pub fn soft_dirty_pages(start: Address, end: Address) -> io::Result<Vec<Address>> {
+    let first_page = start.as_usize() >> LOG_BYTES_IN_PAGE;
+    let n_pages = end.diff(start) >> LOG_BYTES_IN_PAGE;
+
+    let mut entries = vec![0u8; n_pages * 8];
+    File::open("/proc/self/pagemap")?.read_exact_at(&mut entries, first_page as u64 * 8)?;
+
+    Ok(entries
+        .chunks_exact(8)
+        .enumerate()
+        .filter(|(_, x)| u64::from_ne_bytes((*x).try_into().unwrap()) & SOFT_DIRTY_BIT != 0)
+        .map(|(i, _)| start.plus(i << LOG_BYTES_IN_PAGE))
+        .collect())
+}
+
+impl ImmixSpace {
+    /// Finds the stores into old objects from the soft-dirty bits of the pages of the space, so
+    /// that mutators (C code which can not be instrumented, say) do not have to store references
+    /// through `ImmixMutatorLocal::write_ref`. Nursery collections then scan the old objects in
+    /// every page written to since the last collection. Fails if the kernel does not track
+    /// soft-dirty bits. Must be called before any mutator is created.
+    pub fn enable_soft_dirty_tracking(&self) -> io::Result<()> {
+        self.check_soft_dirty_tracking();
+
+        // the first word of the space is written to with the bits cleared, so it has to show up
+        let probe = self.start().to_ptr_mut::<usize>();
+        clear_soft_dirty()?;
+        unsafe { probe.write_volatile(probe.read_volatile()) };
+        if soft_dirty_pages(self.start(), self.start().plus(BYTES_IN_PAGE))?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the kernel does not track soft-dirty bits (CONFIG_MEM_SOFT_DIRTY)",
+            ));
+        }
+
+        self.set_write_barrier(WriteBarrier::SoftDirty);
+        clear_soft_dirty()
+    }
+
+    /// Dirties the cards of the pages written to since the last collection, and clears the
+    /// soft-dirty bits for the next one. Only objects starting in a dirty card are scanned, so the
+    /// card of an object which starts before a dirty page and reaches into it is dirtied too.
+    pub(super) fn mark_soft_dirty_cards(&self) {
+        let pages = soft_dirty_pages(self.start(), self.nursery_start())
+            .and_then(|pages| clear_soft_dirty().map(|_| pages))
+            .expect("failed to read the soft-dirty bits of the space");
+
+        for page in pages {
+            if let Some(obj) = self.object_reaching_into(page) {
+                self.remset.cards.mark(obj);
+            }
+            for card in (0..BYTES_IN_PAGE).step_by(BYTES_IN_CARD) {
+                self.remset.cards.mark(page.plus(card));
+            }
+        }
+    }
+
+    /// The start of the object which starts before `page` and ends in it, if there is one. Objects
+    /// never cross into another block, so only the block holding `page` is searched.
+    fn object_reaching_into(&self, page: Address) -> Option<Address> {
+        let block_start = page.align_down(self.geometry().bytes_in_block());
+        let mut addr = page;
+        while addr > block_start {
+            addr = addr.minus(size_of::<*mut ()>());
+            if self.object_starts.is_set(addr) {
+                let size = self.object_size(unsafe { addr.to_object_reference() });
+                return (addr.plus(size) > page).then_some(addr);
+            }
+        }
+
+        None
+    }
+}
@@ -0,0 +1,53 @@
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// Bookkeeping for sticky mark bits (Sticky Immix). Nursery collections leave the mark state alone,
+/// so every object that survived an earlier collection stays marked, and its lines stay live. The
+/// trace then only visits objects allocated since the last collection, starting from the roots and
+/// from the remembered set filled by the write barrier (see `RememberedSet`). Every
+/// `full_gc_interval`th collection advances the mark state and traces the whole heap, which is the
+/// only way dead old objects are reclaimed.
+#[derive(Default)]
+pub struct StickyState {
+    enabled: AtomicBool,
+    full_gc_interval: AtomicUsize,
+    // nursery collections since the last full-heap one
+    nursery_gcs: AtomicUsize,
+}
+
+impl StickyState {
+    pub fn new() -> StickyState {
+        StickyState {
+            enabled: AtomicBool::new(false),
+            full_gc_interval: AtomicUsize::new(0),
+            nursery_gcs: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn enable(&self, full_gc_interval: usize) {
+        assert!(full_gc_interval > 0, "full_gc_interval must be at least 1");
+
+        self.full_gc_interval
+            .store(full_gc_interval, Ordering::SeqCst);
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// Decides whether the collection about to start has to trace the whole heap.
+    pub fn is_full_heap_next(&self) -> bool {
+        !self.is_enabled()
+            || self.nursery_gcs.load(Ordering::SeqCst) + 1
+                >= self.full_gc_interval.load(Ordering::SeqCst)
+    }
+
+    pub fn finish_collection(&self, full_heap: bool) {
+        if full_heap {
+            self.nursery_gcs.store(0, Ordering::SeqCst);
+        } else {
+            self.nursery_gcs.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
@@ -0,0 +1,203 @@
+use crate::heap::immix::ImmixSpace;
+
+use log::trace;
+use parking_lot::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+/// State of sweeping. Sweeping is always split across the GC threads, which take the blocks to
+/// sweep from a shared queue. With concurrent or lazy sweeping, the rest of the queue is left for
+/// after the pause:
+///
+/// * Concurrent sweeping keeps the GC threads sweeping once mutators resume. The pause only sweeps
+///   until it has found a free block for mutators, which are handed the others as they are swept.
+/// * Lazy sweeping leaves every block to mutators, which sweep one whenever they need a block and
+///   the usable ones are gone. Sweeping then costs nothing but the blocks mutators get to. Since
+///   the pause no longer finds out whether the space is exhausted, a lazy sweep which ends without
+///   a single free block makes the next pause sweep until it finds one, as concurrent sweeping
+///   does. Lazy sweeping does not apply to generational spaces, whose mutators only take nursery
+///   blocks, so the pause sweeps the mature space as if it was off.
+///
+/// In both cases mutators sweep blocks themselves rather than wait, and the next collection sweeps
+/// whatever is left before it starts. A mutator only waits once the queue is empty, for the GC
+/// threads to finish the few blocks they are still sweeping.
+#[derive(Default)]
+pub struct SweepState {
+    concurrent: AtomicBool,
+    lazy: AtomicBool,
+    // set when the last deferred sweep found no free block, until the next pause sweeps
+    exhausted: AtomicBool,
+    // set while the GC threads sweep concurrently, and only cleared under `lock`, so mutators
+    // waiting for them on `done` are woken
+    sweeping: AtomicBool,
+    lock: Mutex<()>,
+    done: Condvar,
+    // whether the blocks left over are swept as after a full-heap collection
+    full_heap: AtomicBool,
+    // the counts of the sweep left unfinished by the last pause
+    stats: Mutex<Option<SweepStats>>,
+}
+
+impl SweepState {
+    pub fn new() -> SweepState {
+        SweepState::default()
+    }
+
+    pub fn enable_concurrent(&self) {
+        self.concurrent.store(true, Ordering::SeqCst);
+    }
+
+    pub fn enable_lazy(&self) {
+        self.lazy.store(true, Ordering::SeqCst);
+    }
+
+    #[inline(always)]
+    pub fn is_concurrent(&self) -> bool {
+        self.concurrent.load(Ordering::Relaxed)
+    }
+
+    #[inline(always)]
+    pub fn is_lazy(&self) -> bool {
+        self.lazy.load(Ordering::Relaxed)
+    }
+
+    /// Checks if the last deferred sweep left the space without a free block, and forgets it.
+    pub fn take_exhausted(&self) -> bool {
+        self.exhausted.swap(false, Ordering::SeqCst)
+    }
+
+    /// Checks if the GC threads are still sweeping concurrently.
+    #[inline(always)]
+    pub fn is_sweeping(&self) -> bool {
+        self.sweeping.load(Ordering::SeqCst)
+    }
+
+    #[inline(always)]
+    pub fn is_full_heap(&self) -> bool {
+        self.full_heap.load(Ordering::Relaxed)
+    }
+
+    /// Leaves the rest of a sweep for after the pause, with the counts of the blocks swept so far.
+    pub fn defer(&self, full_heap: bool, stats: SweepStats) {
+        self.full_heap.store(full_heap, Ordering::SeqCst);
+        *self.stats.lock() = Some(stats);
+    }
+
+    /// Adds the counts of blocks swept after the pause.
+    pub fn add(&self, stats: &SweepStats) {
+        if let Some(total) = self.stats.lock().as_mut() {
+            total.add(stats);
+        }
+    }
+
+    /// Takes the counts of the deferred sweep, if there is one.
+    pub fn take_stats(&self) -> Option<SweepStats> {
+        self.stats.lock().take()
+    }
+
+    /// Records that the GC threads sweep the rest of the blocks, until one of them calls `done`.
+    pub fn start(&self) {
+        self.sweeping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn done(&self) {
+        let _lock = self.lock.lock();
+        self.sweeping.store(false, Ordering::SeqCst);
+        self.done.notify_all();
+    }
+
+    /// Waits for the GC threads to finish sweeping concurrently, if they are.
+    pub fn wait(&self) {
+        let mut lock = self.lock.lock();
+        while self.is_sweeping() {
+            self.done.wait(&mut lock);
+        }
+    }
+}
+
+/// Held by each GC thread sweeping concurrently. The last one to finish ends concurrent sweeping,
+/// and so does the first to panic, so mutators waiting for it are not left waiting forever. The
+/// panic is resumed by the next collection (see `ImmixSpace::finish_sweeping`).
+pub struct SweepingDone<'a> {
+    state: &'a SweepState,
+    // the threads still sweeping
+    running: &'a AtomicUsize,
+}
+
+impl<'a> SweepingDone<'a> {
+    pub fn new(state: &'a SweepState, running: &'a AtomicUsize) -> SweepingDone<'a> {
+        SweepingDone { state, running }
+    }
+}
+
+impl Drop for SweepingDone<'_> {
+    fn drop(&mut self) {
+        if self.running.fetch_sub(1, Ordering::SeqCst) == 1 || thread::panicking() {
+            trace!("concurrent sweeping done");
+            self.state.done();
+        }
+    }
+}
+
+/// Block counts gathered while sweeping, or while rebuilding the block lists after compaction.
+#[derive(Default)]
+pub struct SweepStats {
+    pub free_lines: usize,
+    // free blocks handed to mutators, and those held back in the defrag reserve
+    pub free_blocks: usize,
+    pub reserved_blocks: usize,
+    pub recyclable_blocks: usize,
+    pub recyclable_lines: usize,
+    pub full_blocks: usize,
+    // blocks left as the last sweep found them, since nothing in them can have changed
+    pub skipped_blocks: usize,
+}
+
+impl SweepStats {
+    /// Adds the counts gathered by another thread.
+    pub fn add(&mut self, other: &SweepStats) {
+        self.free_lines += other.free_lines;
+        self.free_blocks += other.free_blocks;
+        self.reserved_blocks += other.reserved_blocks;
+        self.recyclable_blocks += other.recyclable_blocks;
+        self.recyclable_lines += other.recyclable_lines;
+        self.full_blocks += other.full_blocks;
+        self.skipped_blocks += other.skipped_blocks;
+    }
+}
+
+impl ImmixSpace {
+    /// Lets sweeping continue after mutators resume, so pauses only sweep until mutators have a
+    /// free block to allocate into. Must be called before the first collection.
+    pub fn enable_concurrent_sweeping(&self) {
+        self.sweeping.enable_concurrent();
+    }
+
+    /// Leaves sweeping to mutators, which sweep blocks as they take them, so pauses do not sweep
+    /// at all. Has no effect on generational spaces. Must be called before the first collection.
+    pub fn enable_lazy_sweeping(&self) {
+        self.sweeping.enable_lazy();
+    }
+
+    /// Waits for concurrent sweeping to finish, and sweeps whatever the last pause left unswept.
+    /// Called before each collection, which may not trace or sweep blocks which are still waiting
+    /// to be swept. Returns true if the last sweep was still unfinished, in which case ambiguous
+    /// roots found before this call may refer to objects it has just freed.
+    pub fn finish_sweeping(self: &Arc<Self>) -> bool {
+        self.gc_workers.wait();
+
+        match self.sweeping.take_stats() {
+            Some(mut stats) => {
+                stats.add(&self.sweep_blocks(self.sweeping.is_full_heap(), false));
+                self.sweeping.exhausted.store(
+                    stats.free_blocks + stats.reserved_blocks == 0,
+                    Ordering::SeqCst,
+                );
+                self.finish_sweep(&stats);
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -1,5 +1,10 @@
 mod gc;
 mod immix;
 
-pub use gc::{gc_count, set_low_water_mark};
-pub use immix::{ImmixMutatorLocal, ImmixSpace};
+pub use gc::{
+    add_root, gc_count, gc_pause_time, last_retention_report, max_gc_pause, remove_root,
+    retention_report_enabled, set_low_water_mark, set_retention_report, RetentionReport,
+};
+pub use immix::{
+    BlockGeometry, BlockPolicy, ImmixMutatorLocal, ImmixSpace, MarkingBudget, WriteBarrier,
+};
@@ -1,13 +1,120 @@
 use crate::common::ObjectReference;
 use std::mem::size_of;
+use std::sync::atomic::{AtomicU8, Ordering};
 
 use crate::common::Address;
 
 pub const REF_BITS_LEN: usize = 6;
+/// Set in the encoding of every object passed to `init_object`. It is not kept in the alloc map:
+/// where objects start is recorded in a separate table with a bit for every word (see
+/// `is_object_start`).
 pub const OBJ_START_BIT: usize = 6;
 pub const SHORT_ENCODE_BIT: usize = 7;
 
+/// Alloc map entry written after the last object of an allocation hole. It ends the scan done by
+/// `get_object_size`, and is told apart from an object with the same entry by the object start
+/// bits.
+pub const HOLE_END: u8 = 1 << SHORT_ENCODE_BIT;
+
+/// Alloc map entries of an object which is being copied by another GC thread, and of an object
+/// which has been copied. A copied object keeps the address of its new copy in its first word, and
+/// loses its object start bit. The alloc map entries of objects never have `FORWARDING_BIT` set.
+const FORWARDING_BIT: usize = OBJ_START_BIT;
+pub const BEING_FORWARDED: u8 = 1 << FORWARDING_BIT | 0b01;
+pub const FORWARDED: u8 = 1 << FORWARDING_BIT | 0b10;
+
+/// The alloc map entry of an object with the given encoding.
+#[inline(always)]
+pub fn alloc_map_entry(encode: u8) -> u8 {
+    debug_assert!(
+        encode & (1 << OBJ_START_BIT) != 0 && encode & (1 << SHORT_ENCODE_BIT) != 0,
+        "objects are encoded with the start and short encoding bits set"
+    );
+    encode & !(1 << OBJ_START_BIT)
+}
+
 #[inline(always)]
 pub unsafe fn get_ref_byte(alloc_map: *mut u8, space_start: Address, obj: ObjectReference) -> u8 {
     *alloc_map.add(obj.to_address().diff(space_start) / size_of::<*mut ()>())
 }
+
+/// Checks the bit of `obj` in the object start bits, a table with a bit for every word of the
+/// space. The bit of word `i` is bit `i % 8` of byte `i / 8`.
+#[inline(always)]
+pub unsafe fn is_object_start(
+    object_starts: *mut u8,
+    space_start: Address,
+    obj: ObjectReference,
+) -> bool {
+    if !obj.as_usize().is_multiple_of(size_of::<*mut ()>()) {
+        return false;
+    }
+
+    let word = obj.to_address().diff(space_start) / size_of::<*mut ()>();
+    AtomicU8::from_ptr(object_starts.add(word / 8)).load(Ordering::Relaxed) & (1 << (word % 8)) != 0
+}
+
+/// Records that an object starts at `addr`. The table is also updated by GC threads while mutators
+/// run (sweeping and marking concurrently), so the bit is set atomically.
+#[inline(always)]
+pub unsafe fn set_object_start(object_starts: *mut u8, space_start: Address, addr: Address) {
+    let word = addr.diff(space_start) / size_of::<*mut ()>();
+    AtomicU8::from_ptr(object_starts.add(word / 8)).fetch_or(1 << (word % 8), Ordering::Relaxed);
+}
+
+/// The reference fields of an object with a short encoding, given its alloc map entry.
+#[inline(always)]
+pub fn ref_slots(encode: u8, obj: ObjectReference) -> impl Iterator<Item = Address> {
+    let ref_bits = encode & ((1 << REF_BITS_LEN) - 1);
+    (0..REF_BITS_LEN)
+        .filter(move |i| ref_bits & (1 << i) != 0)
+        .map(move |i| obj.to_address().plus(i * size_of::<*mut ()>()))
+}
+
+/// Writes `HOLE_END` after the last object allocated in a hole, unless the next object already
+/// starts there. `cursor` is where the next object would have been allocated.
+#[inline(always)]
+pub unsafe fn finish_hole(
+    alloc_map: *mut u8,
+    object_starts: *mut u8,
+    space_start: Address,
+    cursor: Address,
+    block_end: Address,
+) {
+    let end = cursor.align_up(size_of::<*mut ()>());
+    if end < block_end && !is_object_start(object_starts, space_start, end.to_object_reference()) {
+        *alloc_map.add(end.diff(space_start) / size_of::<*mut ()>()) = HOLE_END;
+    }
+}
+
+/// Gives atomic access to the alloc map entry of `obj`. Used by GC threads to race for copying an
+/// object.
+#[inline(always)]
+pub unsafe fn get_ref_byte_atomic<'a>(
+    alloc_map: *mut u8,
+    space_start: Address,
+    obj: ObjectReference,
+) -> &'a AtomicU8 {
+    AtomicU8::from_ptr(alloc_map.add(obj.to_address().diff(space_start) / size_of::<*mut ()>()))
+}
+
+/// Objects do not have a header, so the size of an object is taken to be the distance to the next
+/// non-empty entry in the alloc map (either the next object or the end of the hole the object was
+/// allocated in). This includes any alignment padding after the object. `limit` bounds the scan
+/// and should be the end of the block containing the object.
+pub unsafe fn get_object_size(
+    alloc_map: *mut u8,
+    space_start: Address,
+    obj: ObjectReference,
+    limit: Address,
+) -> usize {
+    let start = obj.to_address();
+    let mut cursor = start.plus(size_of::<*mut ()>());
+
+    while cursor < limit && get_ref_byte(alloc_map, space_start, cursor.to_object_reference()) == 0
+    {
+        cursor = cursor.plus(size_of::<*mut ()>());
+    }
+
+    cursor.diff(start)
+}